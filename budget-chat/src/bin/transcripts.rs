@@ -0,0 +1,72 @@
+//! Searches the transcripts of a budget-chat server and prints the matching
+//! entries as JSON lines, oldest first.
+//!
+//! Usage: `transcripts <dir> [--user <name>] [--room <room>] [--text <text>]
+//! [--since <unix ms>] [--until <unix ms>]`
+
+use std::{env, io::Write, process::ExitCode};
+
+use budget_chat::{search, Query};
+
+struct Args {
+    dir: String,
+    query: Query,
+}
+
+const USAGE: &str = "Usage: transcripts <dir> [--user <name>] [--room <room>] [--text <text>] \
+                     [--since <unix ms>] [--until <unix ms>]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+    let mut dir = None;
+    let mut query = Query::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--user" => query.user = Some(value()?),
+            "--room" => query.room = Some(value()?),
+            "--text" => query.text = Some(value()?),
+            "--since" => query.since = Some(value()?.parse().map_err(|_| "Invalid --since time")?),
+            "--until" => query.until = Some(value()?.parse().map_err(|_| "Invalid --until time")?),
+            _ if dir.is_none() => dir = Some(arg),
+            _ => return Err(format!("Unexpected argument: {arg}")),
+        }
+    }
+
+    Ok(Args {
+        dir: dir.ok_or("Missing the transcript directory")?,
+        query,
+    })
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let entries = match search(&args.dir, &args.query) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Cannot search {}: {}", args.dir, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    for entry in entries {
+        // Entries always serialize
+        let line = serde_json::to_string(&entry).unwrap();
+        if writeln!(stdout, "{line}").is_err() {
+            // Closed pipe, such as `| head`
+            break;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,542 @@
+use std::{collections::HashMap, future::Future, net::SocketAddr, pin::Pin};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    db::Db,
+    history::History,
+    rooms::RoomName,
+    transcript::{Event, Transcript},
+    users::{broadcast, room_list, UserStream, Username},
+};
+
+/// What the connection does once a command ran.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Continue,
+    /// Leave the chat, as if the client disconnected.
+    Quit,
+}
+
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Outcome>> + Send + 'a>>;
+
+/// A `/name args` line a joined user can send instead of a chat message.
+///
+/// Errors returned by [`Command::run`] close the connection, like failed
+/// writes do. Mistakes of the user get a reply instead.
+pub trait Command: Send + Sync {
+    /// Name typed after the slash.
+    fn name(&self) -> &'static str;
+    /// Line shown by `/help`, such as `/join <room>: switch to another room`.
+    fn help(&self) -> &'static str;
+    /// Runs the command with the rest of the line, after the first space.
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, args: &'a str) -> CommandFuture<'a>;
+}
+
+/// The user running a command.
+pub struct Context<'a> {
+    pub db: &'a mut Db,
+    pub username: &'a mut Username,
+    pub connection: &'a UserStream,
+    pub commands: &'a Commands,
+    pub history: &'a History,
+    pub transcript: &'a Transcript,
+    pub peer: SocketAddr,
+}
+
+/// Commands known to the server.
+///
+/// Only lines naming a known command are intercepted. Any other line,
+/// including `/words` that are not commands, is broadcast verbatim, as plain
+/// budgetchat clients expect. In strict mode there are no commands at all.
+pub struct Commands {
+    commands: HashMap<&'static str, Box<dyn Command>>,
+    strict: bool,
+}
+
+impl<'a> Context<'a> {
+    pub async fn reply(&self, message: &str) -> anyhow::Result<()> {
+        self.connection.send(message).await
+    }
+
+    /// Sends `message` to everyone else in the room of the user.
+    pub async fn broadcast(&self, message: &str) {
+        let other_users = self.db.get_roommates(self.username).await;
+        broadcast(&other_users, message).await;
+    }
+
+    /// Adds `event` of the user to the transcript.
+    pub fn log(&self, event: Event) {
+        self.transcript.record(self.peer, self.username, event);
+    }
+
+    /// Sends `text` to everyone else in the room of the user, as
+    /// `[name] text`, and keeps it in the history of the room.
+    pub async fn say(&self, text: &str) {
+        let line = format!("[{}] {}", self.username, text);
+        self.send_to_room(&line, |room| Event::Message {
+            room,
+            text: text.to_string(),
+        })
+        .await;
+    }
+
+    /// Tells everyone else in the room of the user what they are doing, as
+    /// `* name action`, and keeps it in the history of the room.
+    pub async fn act(&self, action: &str) {
+        let line = format!("* {} {}", self.username, action);
+        self.send_to_room(&line, |room| Event::Action {
+            room,
+            text: action.to_string(),
+        })
+        .await;
+    }
+
+    /// Sends `line`, without its line feed, to everyone else in the room of
+    /// the user, keeps it in the history of the room and adds the `event` of
+    /// the room to the transcript.
+    async fn send_to_room(&self, line: &str, event: impl FnOnce(String) -> Event) {
+        let room = self.db.get_room(self.username).await;
+
+        let mut log = self.history.lock().await;
+        if let Some(room) = &room {
+            log.record(room, line);
+        }
+        let other_users = self.db.get_roommates(self.username).await;
+        drop(log);
+
+        if let Some(room) = room {
+            self.log(event(room.to_string()));
+        }
+        broadcast(&other_users, &format!("{line}\n")).await;
+    }
+}
+
+impl Commands {
+    /// The built-in commands.
+    pub fn new() -> Self {
+        let mut commands = Self {
+            commands: HashMap::new(),
+            strict: false,
+        };
+        commands.register(Help);
+        commands.register(Who);
+        commands.register(Me);
+        commands.register(Nick);
+        commands.register(Msg);
+        commands.register(Join);
+        commands.register(Leave);
+        commands.register(Rooms);
+        commands.register(Quit);
+
+        commands
+    }
+
+    pub fn strict() -> Self {
+        Self {
+            commands: HashMap::new(),
+            strict: true,
+        }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Adds a command, replacing any command with the same name. Ignored in
+    /// strict mode.
+    pub fn register(&mut self, command: impl Command + 'static) {
+        if !self.strict {
+            self.commands.insert(command.name(), Box::new(command));
+        }
+    }
+
+    /// The command of a `/name args` line and its arguments, or `None` for
+    /// lines to broadcast.
+    pub fn parse<'a>(&'a self, line: &'a str) -> Option<(&'a dyn Command, &'a str)> {
+        if self.strict {
+            return None;
+        }
+
+        let line = line.strip_prefix('/')?;
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        let command = self.commands.get(name)?;
+
+        Some((command.as_ref(), args))
+    }
+
+    /// Help lines of every command, sorted by name.
+    fn help(&self) -> Vec<&'static str> {
+        let mut commands = self.commands.values().collect::<Vec<_>>();
+        commands.sort_by_key(|c| c.name());
+        commands.iter().map(|c| c.help()).collect()
+    }
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Help;
+struct Who;
+struct Me;
+struct Nick;
+struct Msg;
+struct Join;
+struct Leave;
+struct Rooms;
+struct Quit;
+
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn help(&self) -> &'static str {
+        "/help: list the commands"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, _args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            for line in ctx.commands.help() {
+                ctx.reply(&format!("* {line}\n")).await?;
+            }
+
+            Ok(Outcome::Continue)
+        })
+    }
+}
+
+impl Command for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn help(&self) -> &'static str {
+        "/who: list the users in your room"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, _args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let other_users = ctx.db.get_roommates(ctx.username).await;
+            ctx.reply(&room_list(&other_users)).await?;
+
+            Ok(Outcome::Continue)
+        })
+    }
+}
+
+impl Command for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn help(&self) -> &'static str {
+        "/me <action>: tell your room what you are doing"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            if args.is_empty() {
+                ctx.reply("* Usage: /me <action>\n").await?;
+            } else {
+                ctx.act(args).await;
+            }
+
+            Ok(Outcome::Continue)
+        })
+    }
+}
+
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+
+    fn help(&self) -> &'static str {
+        "/nick <newname>: change your name"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let new = match Username::new(args.to_string()) {
+                Ok(new) if new == *ctx.username => {
+                    ctx.reply(&format!("* You are already known as {new}\n"))
+                        .await?;
+                    return Ok(Outcome::Continue);
+                }
+                Ok(new) => new,
+                Err(e) => {
+                    ctx.reply(&format!("* {e}\n")).await?;
+                    return Ok(Outcome::Continue);
+                }
+            };
+
+            match ctx.db.rename_user(ctx.username, &new).await {
+                Ok(room_users) => {
+                    ctx.log(Event::Nick {
+                        new: new.to_string(),
+                    });
+                    let announcement = format!("* {} is now known as {}\n", ctx.username, new);
+                    *ctx.username = new;
+                    ctx.reply(&announcement).await?;
+                    broadcast(&room_users, &announcement).await;
+                }
+                Err(e) => ctx.reply(&format!("* {e}\n")).await?,
+            }
+
+            Ok(Outcome::Continue)
+        })
+    }
+}
+
+impl Command for Msg {
+    fn name(&self) -> &'static str {
+        "msg"
+    }
+
+    fn help(&self) -> &'static str {
+        "/msg <username> <text>: send a message to one user only"
+    }
+
+    /// Sends the text to the named user only, and echoes it back to the
+    /// sender so they can tell it went through.
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let (to, text) = args.split_once(' ').unwrap_or((args, ""));
+            if to.is_empty() || text.is_empty() {
+                ctx.reply("* Usage: /msg <username> <text>\n").await?;
+                return Ok(Outcome::Continue);
+            }
+
+            let recipient = match Username::new(to.to_string()) {
+                Ok(to) => ctx.db.get_user(&to).await,
+                Err(_) => None,
+            };
+            match recipient {
+                Some(recipient) => {
+                    // The recipient's connection cleans up after them if
+                    // they are gone, the sender only needs to know
+                    match recipient
+                        .send(&format!("*{}* {}\n", ctx.username, text))
+                        .await
+                    {
+                        Ok(()) => {
+                            ctx.log(Event::Direct {
+                                to: to.to_string(),
+                                text: text.to_string(),
+                            });
+                            ctx.reply(&format!("-> *{to}* {text}\n")).await?;
+                        }
+                        Err(e) => {
+                            eprintln!("Cannot send to {to}: {e}");
+                            ctx.reply(&format!("* Could not deliver your message to {to}\n"))
+                                .await?;
+                        }
+                    }
+                }
+                None => ctx.reply(&format!("* No user named {to}\n")).await?,
+            }
+
+            Ok(Outcome::Continue)
+        })
+    }
+}
+
+impl Command for Join {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+
+    fn help(&self) -> &'static str {
+        "/join <room>: switch to another room"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            match RoomName::new(args.to_string()) {
+                Ok(room) => join_room(ctx, &room).await?,
+                Err(e) => ctx.reply(&format!("* {e}\n")).await?,
+            }
+
+            Ok(Outcome::Continue)
+        })
+    }
+}
+
+impl Command for Leave {
+    fn name(&self) -> &'static str {
+        "leave"
+    }
+
+    fn help(&self) -> &'static str {
+        "/leave: go back to the main room"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, _args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            join_room(ctx, &RoomName::default()).await?;
+
+            Ok(Outcome::Continue)
+        })
+    }
+}
+
+impl Command for Rooms {
+    fn name(&self) -> &'static str {
+        "rooms"
+    }
+
+    fn help(&self) -> &'static str {
+        "/rooms: list the rooms and how many users are in them"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, _args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let rooms = ctx
+                .db
+                .get_rooms()
+                .await
+                .iter()
+                .map(|(room, count)| format!("{room} ({count})"))
+                .collect::<Vec<String>>();
+            ctx.reply(&format!("* Rooms: {}\n", rooms.join(", ")))
+                .await?;
+
+            Ok(Outcome::Continue)
+        })
+    }
+}
+
+impl Command for Quit {
+    fn name(&self) -> &'static str {
+        "quit"
+    }
+
+    fn help(&self) -> &'static str {
+        "/quit: leave the chat"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context<'_>, _args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            ctx.reply("* Bye\n").await?;
+
+            Ok(Outcome::Quit)
+        })
+    }
+}
+
+async fn join_room(ctx: &mut Context<'_>, room: &RoomName) -> anyhow::Result<()> {
+    // Holding the stream keeps messages from the new room behind its list
+    let stream = ctx.connection.stream();
+    let mut ws = stream.lock().await;
+    let log = ctx.history.lock().await;
+    let current = ctx.db.get_room(ctx.username).await;
+    let Some(change) = ctx.db.join_room(ctx.username, room).await else {
+        drop(log);
+        ws.write_all(format!("* You are already in {room}\n").as_bytes())
+            .await?;
+        return Ok(());
+    };
+    let replay = log.replay(room);
+    drop(log);
+    if let Some(current) = current {
+        ctx.log(Event::Leave {
+            room: current.to_string(),
+        });
+    }
+    ctx.log(Event::Join {
+        room: room.to_string(),
+    });
+    ws.write_all(room_list(&change.joined).as_bytes()).await?;
+    ws.write_all(replay.as_bytes()).await?;
+    drop(ws);
+
+    let username = &ctx.username;
+    broadcast(&change.left, &format!("* {username} has left the room\n")).await;
+    broadcast(
+        &change.joined,
+        &format!("* {username} has entered the room\n"),
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, CommandFuture, Commands, Context, Outcome};
+
+    struct Shrug;
+
+    impl Command for Shrug {
+        fn name(&self) -> &'static str {
+            "shrug"
+        }
+
+        fn help(&self) -> &'static str {
+            "/shrug: ¯\\_(ツ)_/¯"
+        }
+
+        fn run<'a>(&'a self, _ctx: &'a mut Context<'_>, _args: &'a str) -> CommandFuture<'a> {
+            Box::pin(async { Ok(Outcome::Continue) })
+        }
+    }
+
+    fn known(commands: &Commands, line: &str) -> Option<(&'static str, String)> {
+        let (command, args) = commands.parse(line)?;
+
+        Some((command.name(), args.to_string()))
+    }
+
+    #[test]
+    fn dispatches_lines_starting_with_a_slash() {
+        let commands = Commands::new();
+
+        assert_eq!(
+            known(&commands, "/join rust"),
+            Some(("join", "rust".to_string()))
+        );
+        assert_eq!(
+            known(&commands, "/msg bob see you at 5"),
+            Some(("msg", "bob see you at 5".to_string()))
+        );
+        assert_eq!(known(&commands, "/quit"), Some(("quit", String::new())));
+        assert_eq!(
+            known(&commands, "/nick bobby"),
+            Some(("nick", "bobby".to_string()))
+        );
+        assert!(commands.parse("hello /join rust").is_none());
+    }
+
+    #[test]
+    fn broadcasts_unknown_commands() {
+        let commands = Commands::new();
+
+        assert!(commands.parse("/shrug now").is_none());
+        assert!(commands.parse("/").is_none());
+    }
+
+    #[test]
+    fn registers_extensions() {
+        let mut commands = Commands::new();
+
+        commands.register(Shrug);
+
+        assert_eq!(known(&commands, "/shrug"), Some(("shrug", String::new())));
+        assert!(commands.help().contains(&"/shrug: ¯\\_(ツ)_/¯"));
+    }
+
+    #[test]
+    fn broadcasts_everything_in_strict_mode() {
+        let mut commands = Commands::strict();
+
+        commands.register(Shrug);
+
+        assert!(commands.parse("/join rust").is_none());
+        assert!(commands.parse("/shrug").is_none());
+        assert!(commands.help().is_empty());
+    }
+}
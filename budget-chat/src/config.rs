@@ -0,0 +1,78 @@
+use std::{env, path::PathBuf, str::FromStr};
+
+const DEFAULT_HISTORY_ROOMS: usize = 1024;
+const DEFAULT_TRANSCRIPT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_TRANSCRIPT_MAX_FILES: usize = 100;
+
+/// Server settings, shared by every connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Broadcast every line verbatim, commands included.
+    pub strict_protocol: bool,
+    /// Recent messages kept per room and replayed to users joining it. 0
+    /// disables the history.
+    pub history_len: usize,
+    /// Rooms with a history. Past this, the room written to least recently
+    /// loses its history. The default room always keeps its own.
+    pub history_rooms: usize,
+    /// File the history is saved to, so it survives a restart. `None` keeps
+    /// it in memory only.
+    pub history_file: Option<PathBuf>,
+    /// Directory of the transcript of every message, join and leave. `None`
+    /// disables the transcript.
+    pub transcript_dir: Option<PathBuf>,
+    /// Size at which the transcript file is rotated.
+    pub transcript_max_bytes: u64,
+    /// Rotated transcript files kept. Older ones are deleted.
+    pub transcript_max_files: usize,
+}
+
+impl Config {
+    /// Reads the configuration from the environment, falling back to the
+    /// defaults for missing or unparsable values.
+    ///
+    /// - `STRICT_PROTOCOL` (`1` or `true` to enable)
+    /// - `HISTORY_LEN`
+    /// - `HISTORY_ROOMS` (1024 by default)
+    /// - `HISTORY_FILE`
+    /// - `TRANSCRIPT_DIR`
+    /// - `TRANSCRIPT_MAX_BYTES` (10 MiB by default)
+    /// - `TRANSCRIPT_MAX_FILES` (100 by default)
+    pub fn from_env() -> Self {
+        Self {
+            strict_protocol: env_flag("STRICT_PROTOCOL"),
+            history_len: env_parse("HISTORY_LEN").unwrap_or_default(),
+            history_rooms: env_parse("HISTORY_ROOMS").unwrap_or(DEFAULT_HISTORY_ROOMS),
+            history_file: env::var("HISTORY_FILE").ok().map(PathBuf::from),
+            transcript_dir: env::var("TRANSCRIPT_DIR").ok().map(PathBuf::from),
+            transcript_max_bytes: env_parse("TRANSCRIPT_MAX_BYTES")
+                .unwrap_or(DEFAULT_TRANSCRIPT_MAX_BYTES),
+            transcript_max_files: env_parse("TRANSCRIPT_MAX_FILES")
+                .unwrap_or(DEFAULT_TRANSCRIPT_MAX_FILES),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            strict_protocol: false,
+            history_len: 0,
+            history_rooms: DEFAULT_HISTORY_ROOMS,
+            history_file: None,
+            transcript_dir: None,
+            transcript_max_bytes: DEFAULT_TRANSCRIPT_MAX_BYTES,
+            transcript_max_files: DEFAULT_TRANSCRIPT_MAX_FILES,
+        }
+    }
+}
+
+fn env_flag(key: &str) -> bool {
+    env::var(key)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+fn env_parse<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|v| v.parse().ok())
+}
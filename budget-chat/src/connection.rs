@@ -7,22 +7,49 @@ use tokio::{
 };
 
 use crate::{
+    commands::{Commands, Context, Outcome},
     db::Db,
-    users::{UserStream, Username},
+    history::History,
+    rooms::RoomName,
+    transcript::{Event, Transcript},
+    users::{broadcast, room_list, UserStream, Username},
 };
 
 pub struct Connection {
     stream: TcpStream,
     db: Db,
+    commands: Arc<Commands>,
+    history: History,
+    transcript: Transcript,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, db: Db) -> Self {
-        Self { stream, db }
+    pub fn new(
+        stream: TcpStream,
+        db: Db,
+        commands: Arc<Commands>,
+        history: History,
+        transcript: Transcript,
+    ) -> Self {
+        Self {
+            stream,
+            db,
+            commands,
+            history,
+            transcript,
+        }
     }
 
-    pub async fn process(mut self) -> anyhow::Result<()> {
-        let (rs, mut ws) = self.stream.into_split();
+    pub async fn process(self) -> anyhow::Result<()> {
+        let Self {
+            stream,
+            mut db,
+            commands,
+            history,
+            transcript,
+        } = self;
+        let peer = stream.peer_addr()?;
+        let (rs, mut ws) = stream.into_split();
 
         ws.write_all("Welcome to budgetchat! What shall I call you?\n".as_bytes())
             .await?;
@@ -30,7 +57,7 @@ impl Connection {
         let buf_reader = BufReader::new(rs);
         let mut buf_lines = buf_reader.lines();
 
-        let username = match buf_lines.next_line().await? {
+        let mut username = match buf_lines.next_line().await? {
             Some(username) => Username::new(username)?,
             None => {
                 println!("Client disconnected");
@@ -38,55 +65,328 @@ impl Connection {
             }
         };
 
-        // Announce chat that another user joined
-        let active_users = self.db.get_users().await;
-        for connection in active_users.values() {
-            let stream = connection.stream();
-            let mut stream = stream.lock().await;
-            stream
-                .write_all(format!("* {} has entered the room\n", username).as_bytes())
-                .await?;
-        }
+        let write_stream = Arc::new(Mutex::new(ws));
+        let connection = UserStream::new(write_stream.clone());
 
-        // Present to current user who's in the room (if any)
-        let active_users_names = active_users
-            .keys()
-            .map(|k| format!("{k}"))
-            .collect::<Vec<String>>();
+        // Present to current user who's in the room (if any), then what was
+        // said before. Holding the stream keeps messages from the room behind
+        // these
+        let mut ws = write_stream.lock().await;
+        let log = history.lock().await;
+        let room_users = db.add_user(&username, &connection).await?;
+        let replay = log.replay(&RoomName::default());
+        drop(log);
+        transcript.record(
+            peer,
+            &username,
+            Event::Join {
+                room: RoomName::default().to_string(),
+            },
+        );
 
-        let active_users_list = active_users_names.join(", ");
-        ws.write_all(format!("* The room contains: {}\n", active_users_list).as_bytes())
-            .await?;
+        // From here on the user is in the chat, and has to leave it however
+        // the session ends
+        let session = async {
+            ws.write_all(room_list(&room_users).as_bytes()).await?;
+            ws.write_all(replay.as_bytes()).await?;
+            drop(ws);
 
-        let write_stream = Arc::new(Mutex::new(ws));
-        let connection = UserStream::new(write_stream.clone());
-        self.db.add_user(&username, &connection).await?;
-
-        while let Some(line) = buf_lines.next_line().await? {
-            let users = self.db.get_users().await;
-            let other_users = users
-                .iter()
-                .filter(|(u, _)| **u != username)
-                .map(|(_, c)| c);
-            for connection in other_users {
-                let stream = connection.stream();
-                let mut stream = stream.lock().await;
-                stream
-                    .write_all(format!("[{}] {}\n", username, line).as_bytes())
-                    .await?;
+            // Announce to the room that another user joined
+            broadcast(
+                &room_users,
+                &format!("* {} has entered the room\n", username),
+            )
+            .await;
+
+            while let Some(line) = buf_lines.next_line().await? {
+                let mut ctx = Context {
+                    db: &mut db,
+                    username: &mut username,
+                    connection: &connection,
+                    commands: &commands,
+                    history: &history,
+                    transcript: &transcript,
+                    peer,
+                };
+                match commands.parse(&line) {
+                    Some((command, args)) => {
+                        if command.run(&mut ctx, args).await? == Outcome::Quit {
+                            break;
+                        }
+                    }
+                    None => ctx.say(&line).await,
+                }
             }
+
+            anyhow::Ok(())
         }
+        .await;
 
-        self.db.remove_user(&username).await;
-        let active_users = self.db.get_users().await;
-        for connection in active_users.values() {
-            let stream = connection.stream();
-            let mut stream = stream.lock().await;
-            stream
-                .write_all(format!("* {} has left the room\n", username).as_bytes())
-                .await?;
+        if let Some(room) = db.get_room(&username).await {
+            transcript.record(
+                peer,
+                &username,
+                Event::Leave {
+                    room: room.to_string(),
+                },
+            );
         }
+        let room_users = db.remove_user(&username).await;
+        broadcast(&room_users, &format!("* {} has left the room\n", username)).await;
+
+        session
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+        net::{
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+            TcpListener, TcpStream,
+        },
+        time::timeout,
+    };
+
+    use crate::{search, Commands, Connection, Db, Event, History, Query, Transcript};
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        ws: OwnedWriteHalf,
+    }
+
+    impl Client {
+        /// Connects as `name`, and reads the welcome and the room list.
+        async fn join(addr: SocketAddr, name: &str) -> (Self, String) {
+            let (rs, ws) = TcpStream::connect(addr).await.unwrap().into_split();
+            let mut client = Self {
+                lines: BufReader::new(rs).lines(),
+                ws,
+            };
+            client.recv().await;
+            client.send(name).await;
+            let room_list = client.recv().await;
+
+            (client, room_list)
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.ws
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        async fn recv(&mut self) -> String {
+            timeout(Duration::from_secs(5), self.lines.next_line())
+                .await
+                .expect("no line from the server")
+                .unwrap()
+                .expect("the server closed the connection")
+        }
+    }
+
+    /// Runs a server on a free port, without history or transcript.
+    async fn serve(db: Db) -> SocketAddr {
+        serve_with_transcript(db, Transcript::default()).await
+    }
+
+    async fn serve_with_transcript(db: Db, transcript: Transcript) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let commands = Arc::new(Commands::new());
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let connection = Connection::new(
+                    stream,
+                    db.clone(),
+                    commands.clone(),
+                    History::new(0, 0),
+                    transcript.clone(),
+                );
+                tokio::spawn(connection.process());
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn a_dropped_client_leaves_the_chat() {
+        // Arrange
+        let db = Db::new();
+        let addr = serve(db.clone()).await;
+        let (alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+
+        // Act
+        drop(alice);
+
+        // Assert
+        assert_eq!(bob.recv().await, "* alice has left the room");
+        let (_alice, room_list) = Client::join(addr, "alice").await;
+        assert_eq!(room_list, "* The room contains: bob");
+    }
+
+    #[tokio::test]
+    async fn records_the_leave_of_a_dropped_client() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let transcript = Transcript::open(dir.path(), 1 << 20, 8).unwrap();
+        let addr = serve_with_transcript(Db::new(), transcript.clone()).await;
+        let (alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+
+        // Act
+        drop(alice);
+        bob.recv().await;
+        transcript.flush().await;
+
+        // Assert
+        let alice = Query {
+            user: Some("alice".to_string()),
+            ..Query::default()
+        };
+        let events = search(dir.path(), &alice)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.event)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                Event::Join {
+                    room: "main".to_string()
+                },
+                Event::Leave {
+                    room: "main".to_string()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_client_failing_mid_session_leaves_the_chat() {
+        // Arrange
+        let db = Db::new();
+        let addr = serve(db.clone()).await;
+        let (mut alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+        alice.recv().await;
+
+        // Act
+        alice.ws.write_all(b"\xff\xfe not text\n").await.unwrap();
+
+        // Assert
+        assert_eq!(bob.recv().await, "* alice has left the room");
+        let users = db.get_users().await;
+        assert_eq!(
+            users.keys().map(|u| u.to_string()).collect::<Vec<_>>(),
+            vec!["bob"]
+        );
+        bob.send("/who").await;
+        assert_eq!(bob.recv().await, "* The room contains: ");
+    }
+
+    #[tokio::test]
+    async fn sends_direct_messages_to_the_recipient_only() {
+        // Arrange
+        let addr = serve(Db::new()).await;
+        let (mut alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+        let (mut carol, _) = Client::join(addr, "carol").await;
+        alice.recv().await;
+        alice.recv().await;
+        bob.recv().await;
+
+        // Act
+        alice.send("/msg bob see you at 5").await;
+
+        // Assert
+        assert_eq!(alice.recv().await, "-> *bob* see you at 5");
+        assert_eq!(bob.recv().await, "*alice* see you at 5");
+        // Carol's next line is what the room says next, not the message
+        alice.send("hi all").await;
+        assert_eq!(carol.recv().await, "[alice] hi all");
+        assert_eq!(bob.recv().await, "[alice] hi all");
+    }
+
+    #[tokio::test]
+    async fn tells_the_sender_about_unknown_recipients() {
+        let addr = serve(Db::new()).await;
+        let (mut alice, _) = Client::join(addr, "alice").await;
+
+        alice.send("/msg dave are you there").await;
+
+        assert_eq!(alice.recv().await, "* No user named dave");
+    }
+
+    #[tokio::test]
+    async fn broadcasts_slash_lines_that_are_not_commands() {
+        // Arrange
+        let addr = serve(Db::new()).await;
+        let (mut alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+        alice.recv().await;
+
+        // Act
+        bob.send("/foo bar").await;
+
+        // Assert
+        assert_eq!(alice.recv().await, "[bob] /foo bar");
+        bob.send("/who").await;
+        assert_eq!(bob.recv().await, "* The room contains: alice");
+    }
+
+    #[tokio::test]
+    async fn records_what_users_typed() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let transcript = Transcript::open(dir.path(), 1 << 20, 8).unwrap();
+        let addr = serve_with_transcript(Db::new(), transcript.clone()).await;
+        let (mut alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+        alice.recv().await;
+
+        // Act
+        alice.send("hi").await;
+        alice.send("/me waves").await;
+        assert_eq!(bob.recv().await, "[alice] hi");
+        assert_eq!(bob.recv().await, "* alice waves");
+        transcript.flush().await;
+
+        // Assert
+        let alice = Query {
+            user: Some("alice".to_string()),
+            ..Query::default()
+        };
+        let events = search(dir.path(), &alice)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.event)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events[1..],
+            [
+                Event::Message {
+                    room: "main".to_string(),
+                    text: "hi".to_string()
+                },
+                Event::Action {
+                    room: "main".to_string(),
+                    text: "waves".to_string()
+                },
+            ]
+        );
+        let with_the_name = Query {
+            text: Some("alice".to_string()),
+            ..Query::default()
+        };
+        assert!(search(dir.path(), &with_the_name).unwrap().is_empty());
     }
 }
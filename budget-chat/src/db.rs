@@ -6,43 +6,375 @@ use std::{
 use anyhow::bail;
 use tokio::sync::RwLock;
 
-use crate::users::{UserStream, Username, Users};
+use crate::{
+    rooms::RoomName,
+    users::{UserStream, Username, Users},
+};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Db {
-    active_users: Arc<RwLock<Users>>,
+    state: Arc<RwLock<State>>,
+}
+
+/// Every user is in exactly one room. Rooms are created when someone joins
+/// them and dropped once empty, except for the default room.
+#[derive(Debug, Default)]
+struct State {
+    rooms: HashMap<RoomName, Users>,
+    memberships: HashMap<Username, RoomName>,
+}
+
+/// Who to notify when a user moves to another room.
+#[derive(Debug)]
+pub struct RoomChange {
+    /// Users still in the room that was left.
+    pub left: Users,
+    /// Users already in the room that was joined.
+    pub joined: Users,
 }
 
 impl Db {
     pub fn new() -> Self {
-        Self {
-            active_users: Arc::new(RwLock::new(HashMap::new())),
-        }
+        Self::default()
     }
 
+    /// Every user, whatever their room.
     pub async fn get_users(&self) -> Users {
-        self.active_users.read().await.clone()
+        let state = self.state.read().await;
+        state
+            .rooms
+            .values()
+            .flatten()
+            .map(|(u, c)| (u.clone(), c.clone()))
+            .collect()
+    }
+
+    /// The connection of `username`, whatever their room.
+    pub async fn get_user(&self, username: &Username) -> Option<UserStream> {
+        let state = self.state.read().await;
+        let room = state.memberships.get(username)?;
+        state.rooms.get(room)?.get(username).cloned()
+    }
+
+    /// Users in the same room as `username`, without them.
+    pub async fn get_roommates(&self, username: &Username) -> Users {
+        let state = self.state.read().await;
+        let Some(room) = state.memberships.get(username) else {
+            return Users::new();
+        };
+
+        let mut users = state.rooms.get(room).cloned().unwrap_or_default();
+        users.remove(username);
+        users
+    }
+
+    pub async fn get_room(&self, username: &Username) -> Option<RoomName> {
+        self.state.read().await.memberships.get(username).cloned()
+    }
+
+    /// Every room with the number of users in it, sorted by name.
+    pub async fn get_rooms(&self) -> Vec<(RoomName, usize)> {
+        let state = self.state.read().await;
+        let mut rooms = state
+            .rooms
+            .iter()
+            .map(|(room, users)| (room.clone(), users.len()))
+            .collect::<Vec<_>>();
+        rooms.sort();
+        rooms
     }
 
+    /// Puts the user in the default room and returns the users already in it.
     pub async fn add_user(
         &mut self,
         username: &Username,
         connection: &UserStream,
-    ) -> anyhow::Result<()> {
-        let mut state = self.active_users.write().await;
-        match state.entry(username.clone()) {
+    ) -> anyhow::Result<Users> {
+        let mut state = self.state.write().await;
+        match state.memberships.entry(username.clone()) {
             Entry::Occupied(_) => {
                 eprintln!("Username is taken");
                 bail!("Username is taken");
             }
-            Entry::Vacant(e) => e.insert(connection.clone()),
+            Entry::Vacant(e) => e.insert(RoomName::default()),
+        };
+
+        let room = state.rooms.entry(RoomName::default()).or_default();
+        let users = room.clone();
+        room.insert(username.clone(), connection.clone());
+
+        Ok(users)
+    }
+
+    /// Moves the user to `room`. Returns `None` if they are already in it.
+    pub async fn join_room(&mut self, username: &Username, room: &RoomName) -> Option<RoomChange> {
+        let mut state = self.state.write().await;
+        let current = state.memberships.get(username)?.clone();
+        if current == *room {
+            return None;
+        }
+
+        let (connection, left) = state.leave(username, &current)?;
+        let joined = state.rooms.entry(room.clone()).or_default();
+        let users = joined.clone();
+        joined.insert(username.clone(), connection);
+        state.memberships.insert(username.clone(), room.clone());
+
+        Some(RoomChange {
+            left,
+            joined: users,
+        })
+    }
+
+    /// Gives the user a new name, keeping names unique. Returns the users in
+    /// their room.
+    pub async fn rename_user(
+        &mut self,
+        username: &Username,
+        new: &Username,
+    ) -> anyhow::Result<Users> {
+        let mut state = self.state.write().await;
+        if state.memberships.contains_key(new) {
+            bail!("Username is taken");
+        }
+        let Some(room) = state.memberships.remove(username) else {
+            bail!("Unknown user {username}");
+        };
+
+        let users = state.rooms.entry(room.clone()).or_default();
+        if let Some(connection) = users.remove(username) {
+            users.insert(new.clone(), connection);
+        }
+        let mut others = users.clone();
+        others.remove(new);
+        state.memberships.insert(new.clone(), room);
+
+        Ok(others)
+    }
+
+    /// Returns the users left in the room of the removed user.
+    pub async fn remove_user(&mut self, username: &Username) -> Users {
+        let mut state = self.state.write().await;
+        let Some(room) = state.memberships.remove(username) else {
+            return Users::new();
         };
 
-        Ok(())
+        state
+            .leave(username, &room)
+            .map(|(_, left)| left)
+            .unwrap_or_default()
+    }
+}
+
+impl State {
+    /// Takes the user out of `room`, returning their connection and the users
+    /// still in it.
+    fn leave(&mut self, username: &Username, room: &RoomName) -> Option<(UserStream, Users)> {
+        let users = self.rooms.get_mut(room)?;
+        let connection = users.remove(username)?;
+        let left = users.clone();
+        if left.is_empty() && !room.is_default() {
+            self.rooms.remove(room);
+        }
+
+        Some((connection, left))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::{Barrier, Mutex},
+    };
+
+    use crate::{
+        rooms::RoomName,
+        users::{UserStream, Username, Users},
+        Db,
+    };
+
+    async fn user_stream() -> UserStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_rs, ws) = stream.into_split();
+
+        UserStream::new(Arc::new(Mutex::new(ws)))
+    }
+
+    fn name(name: &str) -> Username {
+        Username::new(name.to_string()).unwrap()
+    }
+
+    fn names(users: &Users) -> Vec<String> {
+        let mut names = users.keys().map(|u| u.to_string()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn tracks_membership_per_room() {
+        // Arrange
+        let mut db = Db::new();
+        let rust = RoomName::new("rust".to_string()).unwrap();
+        for user in ["alice", "bob", "carol"] {
+            db.add_user(&name(user), &user_stream().await)
+                .await
+                .unwrap();
+        }
+
+        // Act
+        let first = db.join_room(&name("alice"), &rust).await.unwrap();
+        let second = db.join_room(&name("bob"), &rust).await.unwrap();
+        let again = db.join_room(&name("bob"), &rust).await;
+
+        // Assert
+        assert_eq!(names(&first.left), vec!["bob", "carol"]);
+        assert!(first.joined.is_empty());
+        assert_eq!(names(&second.left), vec!["carol"]);
+        assert_eq!(names(&second.joined), vec!["alice"]);
+        assert!(again.is_none());
+        assert_eq!(names(&db.get_roommates(&name("alice")).await), vec!["bob"]);
+        assert_eq!(
+            names(&db.get_roommates(&name("carol")).await),
+            Vec::<String>::new()
+        );
+        assert_eq!(names(&db.get_users().await).len(), 3);
+        assert!(db.get_user(&name("alice")).await.is_some());
+        assert!(db.get_user(&name("dave")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_empty_rooms_but_the_default_one() {
+        let mut db = Db::new();
+        let rust = RoomName::new("rust".to_string()).unwrap();
+        db.add_user(&name("alice"), &user_stream().await)
+            .await
+            .unwrap();
+        db.join_room(&name("alice"), &rust).await.unwrap();
+
+        let rooms_while_in_rust = db.get_rooms().await;
+        let left = db.remove_user(&name("alice")).await;
+
+        assert_eq!(
+            rooms_while_in_rust,
+            vec![(RoomName::default(), 0), (rust, 1)]
+        );
+        assert!(left.is_empty());
+        assert_eq!(db.get_rooms().await, vec![(RoomName::default(), 0)]);
+        assert!(db.get_room(&name("alice")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn renames_a_user_in_their_room() {
+        // Arrange
+        let mut db = Db::new();
+        let rust = RoomName::new("rust".to_string()).unwrap();
+        for user in ["alice", "bob", "carol"] {
+            db.add_user(&name(user), &user_stream().await)
+                .await
+                .unwrap();
+        }
+        db.join_room(&name("alice"), &rust).await.unwrap();
+        db.join_room(&name("bob"), &rust).await.unwrap();
+
+        // Act
+        let roommates = db.rename_user(&name("alice"), &name("ally")).await.unwrap();
+        let taken = db.rename_user(&name("bob"), &name("carol")).await;
+
+        // Assert
+        assert_eq!(names(&roommates), vec!["bob"]);
+        assert!(taken.is_err());
+        assert_eq!(db.get_room(&name("ally")).await, Some(rust));
+        assert!(db.get_user(&name("alice")).await.is_none());
+        assert_eq!(names(&db.get_roommates(&name("bob")).await), vec!["ally"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn only_one_user_wins_a_race_for_a_name() {
+        for _ in 0..100 {
+            // Arrange
+            let db = Db::new();
+            for user in ["alice", "bob"] {
+                db.clone()
+                    .add_user(&name(user), &user_stream().await)
+                    .await
+                    .unwrap();
+            }
+            let start = Arc::new(Barrier::new(2));
+
+            // Act
+            let racers = ["alice", "bob"].map(|user| {
+                let mut db = db.clone();
+                let start = start.clone();
+                tokio::spawn(async move {
+                    start.wait().await;
+                    db.rename_user(&name(user), &name("carol")).await.is_ok()
+                })
+            });
+            let mut wins = Vec::new();
+            for racer in racers {
+                wins.push(racer.await.unwrap());
+            }
+
+            // Assert
+            assert_eq!(wins.iter().filter(|won| **won).count(), 1);
+            let loser = if wins[0] { "bob" } else { "alice" };
+            assert_eq!(names(&db.get_users().await), vec![loser, "carol"]);
+            assert_eq!(names(&db.get_roommates(&name("carol")).await), vec![loser]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_new_user_and_a_rename_cannot_share_a_name() {
+        for _ in 0..100 {
+            let db = Db::new();
+            db.clone()
+                .add_user(&name("alice"), &user_stream().await)
+                .await
+                .unwrap();
+            let newcomer = user_stream().await;
+            let start = Arc::new(Barrier::new(2));
+
+            let rename = {
+                let (mut db, start) = (db.clone(), start.clone());
+                tokio::spawn(async move {
+                    start.wait().await;
+                    db.rename_user(&name("alice"), &name("bob")).await.is_ok()
+                })
+            };
+            let join = {
+                let (mut db, start) = (db.clone(), start.clone());
+                tokio::spawn(async move {
+                    start.wait().await;
+                    db.add_user(&name("bob"), &newcomer).await.is_ok()
+                })
+            };
+            let (renamed, joined) = (rename.await.unwrap(), join.await.unwrap());
+
+            assert!(renamed != joined);
+            let expected = if renamed {
+                vec!["bob"]
+            } else {
+                vec!["alice", "bob"]
+            };
+            assert_eq!(names(&db.get_users().await), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_a_taken_username() {
+        let mut db = Db::new();
+        db.add_user(&name("alice"), &user_stream().await)
+            .await
+            .unwrap();
+
+        let taken = db.add_user(&name("alice"), &user_stream().await).await;
 
-    pub async fn remove_user(&mut self, username: &Username) {
-        let mut state = self.active_users.write().await;
-        state.remove(username);
+        assert!(taken.is_err());
     }
 }
@@ -0,0 +1,395 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use tokio::sync::{mpsc, oneshot, Mutex, MutexGuard};
+
+use crate::rooms::RoomName;
+
+/// Messages waiting to be saved. Past this, new messages are kept in memory
+/// only.
+const SAVE_QUEUE_LEN: usize = 1024;
+
+/// Recent messages of every room, replayed to the users joining it.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    log: Arc<Mutex<Log>>,
+}
+
+/// The messages kept for every room, oldest first.
+#[derive(Debug, Default)]
+pub struct Log {
+    messages: Messages,
+    saver: Option<Saver>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Messages {
+    /// Messages kept per room. 0 keeps none.
+    capacity: usize,
+    /// Rooms with a history. Past this, the history of the room that was
+    /// written to least recently is dropped, never the default room's.
+    max_rooms: usize,
+    rooms: HashMap<RoomName, RoomMessages>,
+    /// Bumped on every message, to tell which room was written to last.
+    written: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+struct RoomMessages {
+    messages: VecDeque<String>,
+    /// [`Messages::written`] when the last message was kept.
+    written: u64,
+}
+
+/// Hands messages to the thread saving them, so that no disk IO happens
+/// while the log is locked.
+#[derive(Clone, Debug)]
+struct Saver {
+    sender: mpsc::Sender<Save>,
+    /// Messages not saved because the queue was full.
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+enum Save {
+    Message(RoomName, String),
+    /// Answered once everything sent before is saved.
+    Flush(oneshot::Sender<()>),
+}
+
+/// One `room\tmessage` line per recorded message. Lines are appended as
+/// messages arrive, and the file is rewritten with only the kept messages
+/// once it grows well past them.
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// Lines appended since the file was last rewritten.
+    appended: usize,
+    /// The messages of the file that are kept, to rewrite it with.
+    messages: Messages,
+}
+
+impl History {
+    /// Keeps `capacity` messages for each of at most `max_rooms` rooms.
+    pub fn new(capacity: usize, max_rooms: usize) -> Self {
+        Self {
+            log: Arc::new(Mutex::new(Log {
+                messages: Messages::new(capacity, max_rooms),
+                saver: None,
+            })),
+        }
+    }
+
+    /// Loads the history saved in `path`, if any, and saves new messages to
+    /// it from a thread of its own.
+    pub fn open(capacity: usize, max_rooms: usize, path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut messages = Messages::new(capacity, max_rooms);
+
+        match fs::read_to_string(path) {
+            Ok(saved) => {
+                for line in saved.lines() {
+                    let room = line.split_once('\t').and_then(|(room, message)| {
+                        Some((RoomName::new(room.to_string()).ok()?, message))
+                    });
+                    if let Some((room, message)) = room {
+                        messages.push(&room, message);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let mut log_file = LogFile::create(path.to_path_buf(), messages.clone())?;
+        let (sender, mut receiver) = mpsc::channel(SAVE_QUEUE_LEN);
+
+        thread::spawn(move || {
+            while let Some(save) = receiver.blocking_recv() {
+                match save {
+                    Save::Message(room, message) => {
+                        if let Err(e) = log_file.append(&room, &message) {
+                            eprintln!("Cannot save the history: {e}");
+                        }
+                    }
+                    Save::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            log: Arc::new(Mutex::new(Log {
+                messages,
+                saver: Some(Saver {
+                    sender,
+                    dropped: Arc::default(),
+                }),
+            })),
+        })
+    }
+
+    /// Holding the log while adding a user to a room and reading its
+    /// history, or while recording a message and picking who gets it, makes
+    /// sure a joining user gets every message exactly once.
+    pub async fn lock(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().await
+    }
+
+    /// Waits until the messages recorded so far are saved.
+    pub async fn flush(&self) {
+        let Some(saver) = self.log.lock().await.saver.clone() else {
+            return;
+        };
+
+        let (done, saved) = oneshot::channel();
+        if saver.sender.send(Save::Flush(done)).await.is_ok() {
+            let _ = saved.await;
+        }
+    }
+}
+
+impl Log {
+    /// The history of `room`, as lines marked with `* [history]` to send
+    /// right after the list of users in the room.
+    pub fn replay(&self, room: &RoomName) -> String {
+        self.messages
+            .rooms
+            .get(room)
+            .into_iter()
+            .flat_map(|room| &room.messages)
+            .map(|message| format!("* [history] {message}\n"))
+            .collect()
+    }
+
+    /// Keeps `message`, without its line feed, in the history of `room`. It
+    /// is saved later, or not at all if saving fell too far behind.
+    pub fn record(&mut self, room: &RoomName, message: &str) {
+        if self.messages.capacity == 0 {
+            return;
+        }
+        self.messages.push(room, message);
+
+        let Some(saver) = &self.saver else {
+            return;
+        };
+        match saver
+            .sender
+            .try_send(Save::Message(room.clone(), message.to_string()))
+        {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                let dropped = saver.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    eprintln!("Saving the history fell behind, {dropped} messages not saved");
+                }
+            }
+            // Only once the saving thread died, which already reported why
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+impl Messages {
+    fn new(capacity: usize, max_rooms: usize) -> Self {
+        Self {
+            capacity,
+            max_rooms,
+            ..Self::default()
+        }
+    }
+
+    fn push(&mut self, room: &RoomName, message: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.written += 1;
+        let kept = self.rooms.entry(room.clone()).or_default();
+        kept.written = self.written;
+        kept.messages.push_back(message.to_string());
+        if kept.messages.len() > self.capacity {
+            kept.messages.pop_front();
+        }
+
+        if self.rooms.len() > self.max_rooms {
+            let stalest = self
+                .rooms
+                .iter()
+                .filter(|(room, _)| !room.is_default())
+                .min_by_key(|(_, kept)| kept.written)
+                .map(|(room, _)| room.clone());
+            if let Some(stalest) = stalest {
+                self.rooms.remove(&stalest);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.rooms.values().map(|room| room.messages.len()).sum()
+    }
+}
+
+impl LogFile {
+    fn create(path: PathBuf, messages: Messages) -> io::Result<Self> {
+        Ok(Self {
+            file: save(&path, &messages)?,
+            path,
+            appended: 0,
+            messages,
+        })
+    }
+
+    fn append(&mut self, room: &RoomName, message: &str) -> io::Result<()> {
+        self.messages.push(room, message);
+        self.file
+            .write_all(format!("{room}\t{message}\n").as_bytes())?;
+        self.file.flush()?;
+        self.appended += 1;
+
+        if self.appended > 4 * self.messages.len().max(self.messages.capacity) {
+            self.file = save(&self.path, &self.messages)?;
+            self.appended = 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the file at `path` with `messages`, and opens it for appending.
+fn save(path: &Path, messages: &Messages) -> io::Result<File> {
+    // The room written to last comes last, so that loading the file keeps
+    // the same rooms
+    let mut rooms = messages.rooms.iter().collect::<Vec<_>>();
+    rooms.sort_by_key(|(_, kept)| kept.written);
+    let lines = rooms
+        .iter()
+        .flat_map(|(room, kept)| kept.messages.iter().map(move |m| format!("{room}\t{m}\n")))
+        .collect::<String>();
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, lines)?;
+    fs::rename(&tmp, path)?;
+
+    OpenOptions::new().append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{rooms::RoomName, History};
+
+    fn room(name: &str) -> RoomName {
+        RoomName::new(name.to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_latest_messages_of_every_room() {
+        // Arrange
+        let history = History::new(2, 16);
+        let mut log = history.lock().await;
+
+        // Act
+        for message in ["[alice] one", "[bob] two", "* alice waves"] {
+            log.record(&RoomName::default(), message);
+        }
+        log.record(&room("rust"), "[carol] hi");
+
+        // Assert
+        assert_eq!(
+            log.replay(&RoomName::default()),
+            "* [history] [bob] two\n* [history] * alice waves\n"
+        );
+        assert_eq!(log.replay(&room("rust")), "* [history] [carol] hi\n");
+        assert_eq!(log.replay(&room("empty")), "");
+    }
+
+    #[tokio::test]
+    async fn keeps_nothing_without_a_capacity() {
+        let history = History::new(0, 16);
+        let mut log = history.lock().await;
+
+        log.record(&RoomName::default(), "[alice] one");
+
+        assert_eq!(log.replay(&RoomName::default()), "");
+    }
+
+    #[tokio::test]
+    async fn survives_a_restart() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let history = History::open(3, 16, &path).unwrap();
+        for i in 0..20 {
+            let mut log = history.lock().await;
+            log.record(&RoomName::default(), &format!("[alice] {i}"));
+        }
+        history.flush().await;
+        drop(history);
+
+        // Act
+        let history = History::open(3, 16, &path).unwrap();
+
+        // Assert
+        assert_eq!(
+            history.lock().await.replay(&RoomName::default()),
+            "* [history] [alice] 17\n* [history] [alice] 18\n* [history] [alice] 19\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn rewrites_the_file_with_the_kept_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let history = History::open(2, 16, &path).unwrap();
+
+        for i in 0..9 {
+            history
+                .lock()
+                .await
+                .record(&RoomName::default(), &format!("[alice] {i}"));
+        }
+        history.flush().await;
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "main\t[alice] 7\nmain\t[alice] 8\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_rooms_written_to_last() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let history = History::open(2, 2, &path).unwrap();
+
+        // Act
+        {
+            let mut log = history.lock().await;
+            log.record(&RoomName::default(), "[alice] hi");
+            for name in ["r1", "r2", "r3", "r4"] {
+                log.record(&room(name), &format!("[bob] in {name}"));
+            }
+        }
+        history.flush().await;
+        drop(history);
+        let history = History::open(2, 2, &path).unwrap();
+
+        // Assert
+        let log = history.lock().await;
+        assert_eq!(log.replay(&RoomName::default()), "* [history] [alice] hi\n");
+        assert_eq!(log.replay(&room("r3")), "");
+        assert_eq!(log.replay(&room("r4")), "* [history] [bob] in r4\n");
+    }
+}
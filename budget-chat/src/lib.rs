@@ -1,7 +1,25 @@
+mod commands;
+pub use commands::{Command, CommandFuture, Commands, Context, Outcome};
+
+mod config;
+pub use config::Config;
+
 mod connection;
 pub use connection::Connection;
 
 mod db;
-pub use db::Db;
+pub use db::{Db, RoomChange};
+
+mod history;
+pub use history::{History, Log};
+
+mod rooms;
+pub use rooms::{RoomName, DEFAULT_ROOM};
+
+mod transcript;
+pub use transcript::{
+    search, transcript_files, Entry, Event, Query, Transcript, TranscriptWriter, TRANSCRIPT_FILE,
+};
 
 mod users;
+pub use users::{broadcast, UserStream, Username, Users};
@@ -1,8 +1,16 @@
-use budget_chat::{Connection, Db};
+use std::sync::Arc;
+
+use budget_chat::{Commands, Config, Connection, Db, History, Transcript};
 use tokio::net::{TcpListener, TcpStream};
 
-async fn handle_connection(stream: TcpStream, db: Db) -> anyhow::Result<()> {
-    let connection = Connection::new(stream, db);
+async fn handle_connection(
+    stream: TcpStream,
+    db: Db,
+    commands: Arc<Commands>,
+    history: History,
+    transcript: Transcript,
+) -> anyhow::Result<()> {
+    let connection = Connection::new(stream, db, commands, history, transcript);
     connection.process().await?;
 
     Ok(())
@@ -10,6 +18,7 @@ async fn handle_connection(stream: TcpStream, db: Db) -> anyhow::Result<()> {
 
 #[tokio::main]
 async fn main() {
+    let config = Config::from_env();
     let addr = utils::addr();
     let listener = TcpListener::bind(addr)
         .await
@@ -18,14 +27,38 @@ async fn main() {
     println!("Listening on: {addr}");
 
     let active_users = Db::new();
+    let commands = Arc::new(if config.strict_protocol {
+        Commands::strict()
+    } else {
+        Commands::new()
+    });
+    let history = match config.history_file.as_ref() {
+        Some(path) => History::open(config.history_len, config.history_rooms, path)
+            .expect("Cannot open the history file"),
+        None => History::new(config.history_len, config.history_rooms),
+    };
+    let transcript = match config.transcript_dir.as_ref() {
+        Some(dir) => Transcript::open(
+            dir,
+            config.transcript_max_bytes,
+            config.transcript_max_files,
+        )
+        .expect("Cannot open the transcript"),
+        None => Transcript::default(),
+    };
 
     loop {
         match listener.accept().await {
             Ok((stream, _)) => {
                 let active_users = active_users.clone();
+                let commands = commands.clone();
+                let history = history.clone();
+                let transcript = transcript.clone();
 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_connection(stream, active_users).await {
+                    if let Err(e) =
+                        handle_connection(stream, active_users, commands, history, transcript).await
+                    {
                         eprintln!("{e}");
                     }
                 });
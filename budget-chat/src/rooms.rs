@@ -0,0 +1,39 @@
+use std::fmt::Display;
+
+use anyhow::bail;
+
+/// Room every user is in after picking a name, and goes back to on `/leave`.
+/// Clients that never send a command only ever see this room.
+pub const DEFAULT_ROOM: &str = "main";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RoomName(String);
+
+impl RoomName {
+    pub fn new(name: String) -> anyhow::Result<Self> {
+        let missing_name = name.is_empty();
+        let not_alpha_numeric = name.chars().any(|c| !c.is_alphanumeric());
+
+        if missing_name || not_alpha_numeric {
+            bail!("Room name is not valid")
+        }
+
+        Ok(Self(name))
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.0 == DEFAULT_ROOM
+    }
+}
+
+impl Default for RoomName {
+    fn default() -> Self {
+        Self(DEFAULT_ROOM.to_string())
+    }
+}
+
+impl Display for RoomName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
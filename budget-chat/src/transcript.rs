@@ -0,0 +1,660 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::users::Username;
+
+/// File the transcript is appended to. Once it reaches its size limit, it is
+/// renamed to `transcript-<unix time in ms>-<n>.jsonl` and a new one is
+/// started. Only the most recent rotated files are kept, see
+/// [`TranscriptWriter::open`].
+pub const TRANSCRIPT_FILE: &str = "transcript.jsonl";
+
+/// Entries waiting to be written. Past this, new entries are dropped rather
+/// than holding up the chat.
+const QUEUE_LEN: usize = 4096;
+
+/// One line of the transcript.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Entry {
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+    /// `None` for gaps only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<SocketAddr>,
+    /// `None` for gaps only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Join {
+        room: String,
+    },
+    Leave {
+        room: String,
+    },
+    /// A chat line, as the user typed it.
+    Message {
+        room: String,
+        text: String,
+    },
+    /// A `/me` action, without the name of the user.
+    Action {
+        room: String,
+        text: String,
+    },
+    Direct {
+        to: String,
+        text: String,
+    },
+    Nick {
+        new: String,
+    },
+    /// Entries dropped right before this one, because the transcript fell
+    /// behind.
+    Gap {
+        dropped: u64,
+    },
+}
+
+/// Records chat events to a rotating JSON-lines file, from a thread of its
+/// own so that writing never holds up the delivery of messages.
+///
+/// A disabled transcript, the default, drops every event.
+#[derive(Clone, Debug, Default)]
+pub struct Transcript {
+    sender: Option<mpsc::Sender<Queued>>,
+    /// Entries dropped because the queue was full.
+    dropped: Arc<AtomicU64>,
+    /// Entries dropped since the last gap was queued.
+    missing: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+enum Queued {
+    Entry(Entry),
+    /// Answered once everything sent before is written.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Writes entries to the transcript file of a directory.
+#[derive(Debug)]
+pub struct TranscriptWriter {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    /// Size of the file being written.
+    len: u64,
+}
+
+/// Filters for [`search`]. `None` matches everything. Gaps match any query
+/// covering their time, since the missing entries could have matched.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    pub user: Option<String>,
+    /// Matches joins, leaves, messages and actions of the room.
+    pub room: Option<String>,
+    /// Part of the text of a message or an action.
+    pub text: Option<String>,
+    /// Earliest unix time, in milliseconds.
+    pub since: Option<u64>,
+    /// Latest unix time, in milliseconds.
+    pub until: Option<u64>,
+}
+
+impl Event {
+    fn room(&self) -> Option<&str> {
+        match self {
+            Event::Join { room }
+            | Event::Leave { room }
+            | Event::Message { room, .. }
+            | Event::Action { room, .. } => Some(room),
+            Event::Direct { .. } | Event::Nick { .. } | Event::Gap { .. } => None,
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Event::Message { text, .. }
+            | Event::Action { text, .. }
+            | Event::Direct { text, .. } => Some(text),
+            _ => None,
+        }
+    }
+}
+
+impl Transcript {
+    /// Writes the transcript to `dir`, starting a new file once the current
+    /// one holds `max_bytes` and keeping `max_files` rotated files.
+    pub fn open(dir: impl AsRef<Path>, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let mut writer = TranscriptWriter::open(dir, max_bytes, max_files)?;
+        let (transcript, mut receiver) = Self::queue(QUEUE_LEN);
+
+        thread::spawn(move || {
+            while let Some(queued) = receiver.blocking_recv() {
+                match queued {
+                    Queued::Entry(entry) => {
+                        if let Err(e) = writer.write(&entry) {
+                            eprintln!("Cannot write the transcript: {e}");
+                        }
+                    }
+                    Queued::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        Ok(transcript)
+    }
+
+    fn queue(len: usize) -> (Self, mpsc::Receiver<Queued>) {
+        let (sender, receiver) = mpsc::channel(len);
+        let transcript = Self {
+            sender: Some(sender),
+            dropped: Arc::default(),
+            missing: Arc::default(),
+        };
+
+        (transcript, receiver)
+    }
+
+    /// Queues `event` of `user`. If the queue is full, the event is dropped,
+    /// and a gap entry is queued before the next event that fits.
+    pub fn record(&self, peer: SocketAddr, user: &Username, event: Event) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+
+        let entry = Entry {
+            timestamp: now(),
+            peer: Some(peer),
+            user: Some(user.to_string()),
+            event,
+        };
+        let queued = self.queue_gap(|gap| sender.try_send(gap).is_ok())
+            && match sender.try_send(Queued::Entry(entry)) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => false,
+                // Only once the writer is gone, which already reported why
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+            };
+        if !queued {
+            self.missing.fetch_add(1, Ordering::Relaxed);
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                eprintln!("The transcript fell behind, {dropped} entries dropped");
+            }
+        }
+    }
+
+    /// Queues a gap for the entries dropped since the last one, if any, with
+    /// `send`. `false` if it did not fit.
+    fn queue_gap(&self, send: impl FnOnce(Queued) -> bool) -> bool {
+        let missing = self.missing.swap(0, Ordering::Relaxed);
+        if missing == 0 {
+            return true;
+        }
+
+        let gap = Entry {
+            timestamp: now(),
+            peer: None,
+            user: None,
+            event: Event::Gap { dropped: missing },
+        };
+        if send(Queued::Entry(gap)) {
+            return true;
+        }
+        self.missing.fetch_add(missing, Ordering::Relaxed);
+
+        false
+    }
+
+    /// Entries dropped so far because the transcript fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the entries recorded so far are written.
+    pub async fn flush(&self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+
+        let mut gap = None;
+        self.queue_gap(|queued| {
+            gap = Some(queued);
+            true
+        });
+        if let Some(gap) = gap {
+            let _ = sender.send(gap).await;
+        }
+
+        let (done, written) = oneshot::channel();
+        if sender.send(Queued::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+impl TranscriptWriter {
+    /// Appends to the transcript file of `dir`. Once it holds `max_bytes`,
+    /// it is rotated, and the oldest rotated files are deleted so that at
+    /// most `max_files` are kept. The transcript thus takes about
+    /// `(max_files + 1) * max_bytes` of disk.
+    pub fn open(dir: impl AsRef<Path>, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let file = open_append(&dir.join(TRANSCRIPT_FILE))?;
+        let len = file.metadata()?.len();
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            max_files,
+            file,
+            len,
+        })
+    }
+
+    pub fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        if self.len > 0 && self.len + line.len() as u64 > self.max_bytes {
+            self.rotate(entry.timestamp)?;
+        }
+        self.file.write_all(&line)?;
+        self.len += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self, timestamp: u64) -> io::Result<()> {
+        let current = self.dir.join(TRANSCRIPT_FILE);
+        // Numbered for several rotations within a millisecond
+        let rotated = (0..)
+            .map(|n| {
+                self.dir
+                    .join(format!("transcript-{timestamp:013}-{n:04}.jsonl"))
+            })
+            .find(|path| !path.exists())
+            .unwrap();
+
+        fs::rename(&current, rotated)?;
+        self.file = open_append(&current)?;
+        self.len = 0;
+
+        let files = transcript_files(&self.dir)?;
+        // The current file comes last
+        let rotated = &files[..files.len() - 1];
+        for old in &rotated[..rotated.len().saturating_sub(self.max_files)] {
+            fs::remove_file(old)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Query {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let since = self.since.is_none_or(|since| entry.timestamp >= since);
+        let until = self.until.is_none_or(|until| entry.timestamp <= until);
+        if let Event::Gap { .. } = entry.event {
+            return since && until;
+        }
+
+        let user = self
+            .user
+            .as_ref()
+            .is_none_or(|user| entry.user.as_ref() == Some(user));
+        let room = self
+            .room
+            .as_ref()
+            .is_none_or(|room| entry.event.room() == Some(room.as_str()));
+        let text = self.text.as_ref().is_none_or(|text| {
+            entry
+                .event
+                .text()
+                .is_some_and(|t| t.contains(text.as_str()))
+        });
+
+        user && room && text && since && until
+    }
+}
+
+/// Unix time in milliseconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Transcript files of `dir`, oldest first.
+pub fn transcript_files(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let dir = dir.as_ref();
+    let mut rotated = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("transcript-") && name.ends_with(".jsonl"))
+        })
+        .collect::<Vec<_>>();
+    // Zero-padded timestamps sort in time order
+    rotated.sort();
+
+    let current = dir.join(TRANSCRIPT_FILE);
+    if current.exists() {
+        rotated.push(current);
+    }
+
+    Ok(rotated)
+}
+
+/// Entries of every transcript file of `dir` that match `query`, oldest
+/// first. Lines that are not entries, such as a line cut short by a crash,
+/// are skipped.
+pub fn search(dir: impl AsRef<Path>, query: &Query) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for path in transcript_files(dir)? {
+        for line in BufReader::new(File::open(path)?).lines() {
+            let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+                continue;
+            };
+            if query.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::Queued;
+    use crate::{search, transcript_files, Entry, Event, Query, Transcript, TranscriptWriter};
+
+    fn entry(timestamp: u64, user: &str, event: Event) -> Entry {
+        Entry {
+            timestamp,
+            peer: Some("127.0.0.1:4000".parse().unwrap()),
+            user: Some(user.to_string()),
+            event,
+        }
+    }
+
+    fn message(room: &str, text: &str) -> Event {
+        Event::Message {
+            room: room.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = TranscriptWriter::open(dir.path(), 1 << 20, 100).unwrap();
+
+        writer
+            .write(&entry(
+                1,
+                "alice",
+                Event::Join {
+                    room: "main".to_string(),
+                },
+            ))
+            .unwrap();
+        writer
+            .write(&entry(2, "alice", message("main", "hi")))
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("transcript.jsonl")).unwrap(),
+            concat!(
+                r#"{"timestamp":1,"peer":"127.0.0.1:4000","user":"alice","event":"join","room":"main"}"#,
+                "\n",
+                r#"{"timestamp":2,"peer":"127.0.0.1:4000","user":"alice","event":"message","room":"main","text":"hi"}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn rotates_full_files() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = TranscriptWriter::open(dir.path(), 250, 100).unwrap();
+
+        // Act
+        for i in 0..10 {
+            writer
+                .write(&entry(i, "alice", message("main", &format!("{i}"))))
+                .unwrap();
+        }
+        drop(writer);
+
+        // Assert
+        let files = transcript_files(dir.path()).unwrap();
+        assert!(files.len() > 1);
+        assert!(files.iter().all(|f| fs::metadata(f).unwrap().len() <= 250));
+        assert_eq!(files.last().unwrap(), &dir.path().join("transcript.jsonl"));
+        let timestamps = search(dir.path(), &Query::default())
+            .unwrap()
+            .iter()
+            .map(|e| e.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn searches_by_user_room_text_and_time() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = TranscriptWriter::open(dir.path(), 1 << 20, 100).unwrap();
+        let entries = [
+            entry(10, "alice", message("main", "hello there")),
+            entry(20, "bob", message("rust", "hello crabs")),
+            entry(
+                30,
+                "bob",
+                Event::Direct {
+                    to: "alice".to_string(),
+                    text: "hello you".to_string(),
+                },
+            ),
+            entry(
+                40,
+                "bob",
+                Event::Leave {
+                    room: "rust".to_string(),
+                },
+            ),
+        ];
+        for entry in &entries {
+            writer.write(entry).unwrap();
+        }
+        let search = |query: Query| {
+            search(dir.path(), &query)
+                .unwrap()
+                .iter()
+                .map(|e| e.timestamp)
+                .collect::<Vec<_>>()
+        };
+
+        // Act & Assert
+        assert_eq!(
+            search(Query {
+                text: Some("hello".to_string()),
+                ..Query::default()
+            }),
+            vec![10, 20, 30]
+        );
+        assert_eq!(
+            search(Query {
+                user: Some("bob".to_string()),
+                room: Some("rust".to_string()),
+                ..Query::default()
+            }),
+            vec![20, 40]
+        );
+        assert_eq!(
+            search(Query {
+                since: Some(15),
+                until: Some(30),
+                ..Query::default()
+            }),
+            vec![20, 30]
+        );
+    }
+
+    #[test]
+    fn drops_entries_once_the_queue_is_full() {
+        // Arrange
+        let (transcript, _receiver) = Transcript::queue(2);
+        let user = crate::Username::new("alice".to_string()).unwrap();
+
+        // Act
+        for i in 0..5 {
+            transcript.record(
+                "127.0.0.1:4000".parse().unwrap(),
+                &user,
+                message("main", &format!("{i}")),
+            );
+        }
+
+        // Assert
+        assert_eq!(transcript.dropped(), 3);
+    }
+
+    #[test]
+    fn marks_dropped_entries_with_a_gap() {
+        // Arrange
+        let (transcript, mut receiver) = Transcript::queue(2);
+        let user = crate::Username::new("alice".to_string()).unwrap();
+        let record = |text: &str| {
+            transcript.record(
+                "127.0.0.1:4000".parse().unwrap(),
+                &user,
+                message("main", text),
+            )
+        };
+        for i in 0..5 {
+            record(&format!("{i}"));
+        }
+        let mut written = Vec::new();
+        while let Ok(Queued::Entry(entry)) = receiver.try_recv() {
+            written.push(entry.event);
+        }
+
+        // Act
+        record("caught up");
+        while let Ok(Queued::Entry(entry)) = receiver.try_recv() {
+            written.push(entry.event);
+        }
+
+        // Assert
+        assert_eq!(
+            written,
+            vec![
+                message("main", "0"),
+                message("main", "1"),
+                Event::Gap { dropped: 3 },
+                message("main", "caught up"),
+            ]
+        );
+    }
+
+    #[test]
+    fn gaps_match_every_query_covering_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = TranscriptWriter::open(dir.path(), 1 << 20, 100).unwrap();
+        writer
+            .write(&Entry {
+                timestamp: 10,
+                peer: None,
+                user: None,
+                event: Event::Gap { dropped: 7 },
+            })
+            .unwrap();
+
+        let matching = |query: Query| search(dir.path(), &query).unwrap().len();
+
+        assert_eq!(
+            matching(Query {
+                user: Some("bob".to_string()),
+                room: Some("rust".to_string()),
+                text: Some("hello".to_string()),
+                ..Query::default()
+            }),
+            1
+        );
+        assert_eq!(
+            matching(Query {
+                since: Some(11),
+                ..Query::default()
+            }),
+            0
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("transcript.jsonl")).unwrap(),
+            "{\"timestamp\":10,\"event\":\"gap\",\"dropped\":7}\n"
+        );
+    }
+
+    #[test]
+    fn deletes_the_oldest_rotated_files() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = TranscriptWriter::open(dir.path(), 100, 2).unwrap();
+
+        // Act
+        for i in 0..20 {
+            writer
+                .write(&entry(i, "alice", message("main", &format!("{i}"))))
+                .unwrap();
+        }
+
+        // Assert
+        assert_eq!(transcript_files(dir.path()).unwrap().len(), 3);
+        let timestamps = search(dir.path(), &Query::default())
+            .unwrap()
+            .iter()
+            .map(|e| e.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps.last(), Some(&19));
+        assert!(timestamps.len() < 20);
+        assert_eq!(
+            timestamps,
+            (20 - timestamps.len() as u64..20).collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use std::{collections::HashMap, fmt::Display, sync::Arc};
 
 use anyhow::bail;
-use tokio::{net::tcp::OwnedWriteHalf, sync::Mutex};
+use tokio::{io::AsyncWriteExt, net::tcp::OwnedWriteHalf, sync::Mutex};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Username(String);
@@ -40,6 +40,34 @@ impl UserStream {
     pub fn stream(&self) -> WriteStream {
         self.stream.clone()
     }
+
+    pub async fn send(&self, message: &str) -> anyhow::Result<()> {
+        let mut stream = self.stream.lock().await;
+        stream.write_all(message.as_bytes()).await?;
+
+        Ok(())
+    }
 }
 
 pub type Users = HashMap<Username, UserStream>;
+
+/// Sends `message` to every user in `users`. A user who cannot be reached is
+/// skipped: their own connection notices and cleans up after them.
+pub async fn broadcast(users: &Users, message: &str) {
+    for (username, connection) in users {
+        if let Err(e) = connection.send(message).await {
+            eprintln!("Cannot send to {username}: {e}");
+        }
+    }
+}
+
+/// `* The room contains: a, b`, with the names sorted.
+pub fn room_list(users: &Users) -> String {
+    let mut names = users
+        .keys()
+        .map(|k| format!("{k}"))
+        .collect::<Vec<String>>();
+    names.sort();
+
+    format!("* The room contains: {}\n", names.join(", "))
+}
@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use means_to_an_end::{Price, QueryMessage, SessionPrices, Timestamp};
+
+/// The mean as computed before sessions were indexed: one walk over the period
+/// to count the prices and another one to sum them.
+fn scan_mean(
+    prices: &BTreeMap<Timestamp, Price>,
+    min_time: Timestamp,
+    max_time: Timestamp,
+) -> Price {
+    if min_time > max_time {
+        return 0;
+    }
+
+    let count = prices.range(min_time..=max_time).count();
+    if count == 0 {
+        return 0;
+    }
+
+    let sum = prices
+        .range(min_time..=max_time)
+        .map(|(_ts, price)| *price as i64)
+        .sum::<i64>();
+
+    (sum / count as i64) as Price
+}
+
+fn range_queries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mean over the whole session");
+
+    for size in [1_000, 100_000, 1_000_000] {
+        let prices = (0..size)
+            .map(|ts| (ts, ts % 1_000))
+            .collect::<BTreeMap<_, _>>();
+        let session_prices = prices
+            .iter()
+            .map(|(ts, price)| (*ts, *price))
+            .collect::<SessionPrices>();
+
+        group.bench_with_input(BenchmarkId::new("scan", size), &size, |b, size| {
+            b.iter(|| scan_mean(black_box(&prices), 0, *size))
+        });
+
+        let query_message = QueryMessage::new(0, size);
+        group.bench_with_input(BenchmarkId::new("indexed", size), &size, |b, _| {
+            b.iter(|| query_message.process(black_box(&session_prices)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, range_queries);
+criterion_main!(benches);
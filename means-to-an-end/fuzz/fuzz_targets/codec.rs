@@ -0,0 +1,23 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use means_to_an_end::MeansCodec;
+use tokio_util::codec::Decoder;
+
+// The first byte picks how the rest of the stream is split into reads. The
+// decoder must consume everything it is given without panicking, whatever the
+// split
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk_size, stream)) = data.split_first() else {
+        return;
+    };
+
+    let mut codec = MeansCodec::new();
+    let mut buffer = BytesMut::new();
+    for chunk in stream.chunks(chunk_size.max(1) as usize) {
+        buffer.extend_from_slice(chunk);
+        while let Some(_frame) = codec.decode(&mut buffer).unwrap() {}
+    }
+    let _ = codec.decode_eof(&mut buffer);
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use means_to_an_end::Request;
+
+// Parsing must never panic, and every frame that parses must encode back to
+// the same bytes
+fuzz_target!(|data: &[u8]| {
+    let frame_len = Request::frame_len(data);
+
+    if let Ok(request) = Request::new(data) {
+        assert_eq!(frame_len, Ok(Some(data.len())));
+        assert_eq!(request.to_bytes(), data);
+    }
+});
@@ -0,0 +1,302 @@
+use crate::{ParseError, Price, ProjectResult, SessionPrices, Timestamp};
+
+/// Statistic computed over the prices of a period.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregate {
+    /// Lowest price.
+    Min,
+    /// Highest price.
+    Max,
+    /// Middle price. With an even number of samples, the mean of the two
+    /// middle prices.
+    Median,
+    /// Number of samples.
+    Count,
+    /// Sum of the prices.
+    Sum,
+    /// Population standard deviation of the prices.
+    StdDev,
+    /// Time-weighted average price. Every price holds until the next sample,
+    /// or until the end of the period for the last one. There are no volumes in
+    /// the protocol, so time plays the role that volume plays in a VWAP.
+    TimeWeightedAverage,
+}
+
+impl Aggregate {
+    /// Message type byte of the aggregate, sent instead of `Q`.
+    pub fn type_byte(&self) -> u8 {
+        match self {
+            Aggregate::Min => b'N',
+            Aggregate::Max => b'X',
+            Aggregate::Median => b'M',
+            Aggregate::Count => b'C',
+            Aggregate::Sum => b'S',
+            Aggregate::StdDev => b'D',
+            Aggregate::TimeWeightedAverage => b'W',
+        }
+    }
+
+    pub fn from_type_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'N' => Some(Aggregate::Min),
+            b'X' => Some(Aggregate::Max),
+            b'M' => Some(Aggregate::Median),
+            b'C' => Some(Aggregate::Count),
+            b'S' => Some(Aggregate::Sum),
+            b'D' => Some(Aggregate::StdDev),
+            b'W' => Some(Aggregate::TimeWeightedAverage),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AggregateMessage {
+    aggregate: Aggregate,
+    /// Earliest timestamp of the period.
+    min_time: Timestamp,
+    /// Latest timestamp of the period.
+    max_time: Timestamp,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregateResponse {
+    /// Value of the aggregate over the prices with timestamps T, where
+    /// `min_time` <= T <= `max_time`. If there are no samples, then the
+    /// `value` is 0.
+    ///
+    /// Non-integer values are rounded down.
+    value: i64,
+}
+
+impl AggregateMessage {
+    pub fn new(aggregate: Aggregate, min_time: Timestamp, max_time: Timestamp) -> Self {
+        Self {
+            aggregate,
+            min_time,
+            max_time,
+        }
+    }
+
+    pub fn aggregate(&self) -> Aggregate {
+        self.aggregate
+    }
+
+    pub fn min_time(&self) -> Timestamp {
+        self.min_time
+    }
+
+    pub fn max_time(&self) -> Timestamp {
+        self.max_time
+    }
+
+    pub fn process(&self, session_prices: &SessionPrices) -> ProjectResult<i64> {
+        if self.min_time > self.max_time {
+            return Ok(0);
+        }
+
+        let (count, sum) = session_prices.count_and_sum(self.min_time, self.max_time);
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let samples = || session_prices.range(self.min_time..=self.max_time);
+        let prices = || samples().map(|(_ts, price)| price);
+
+        // Count and sum come from the index, the rest walk the period
+        let value = match self.aggregate {
+            Aggregate::Min => prices().min().unwrap_or_default() as i64,
+            Aggregate::Max => prices().max().unwrap_or_default() as i64,
+            Aggregate::Median => median(prices().collect()),
+            Aggregate::Count => count as i64,
+            Aggregate::Sum => sum,
+            Aggregate::StdDev => std_dev(prices()),
+            Aggregate::TimeWeightedAverage => {
+                time_weighted_average(&samples().collect::<Vec<_>>(), self.max_time)
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+impl AggregateResponse {
+    pub fn new(value: i64) -> Self {
+        Self { value }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    pub fn to_bytes(&self) -> [u8; 8] {
+        self.value.to_be_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> ProjectResult<Self> {
+        match <[u8; 8]>::try_from(bytes) {
+            Ok(bs) => Ok(Self::new(i64::from_be_bytes(bs))),
+            Err(_) => Err(ParseError::InvalidLength.into()),
+        }
+    }
+}
+
+fn median(mut prices: Vec<Price>) -> i64 {
+    prices.sort_unstable();
+
+    let middle = prices.len() / 2;
+    if prices.len() % 2 == 1 {
+        prices[middle] as i64
+    } else {
+        (prices[middle - 1] as i64 + prices[middle] as i64).div_euclid(2)
+    }
+}
+
+fn std_dev(prices: impl Iterator<Item = Price>) -> i64 {
+    let (count, sum, sum_of_squares) = prices.fold((0_i128, 0_i128, 0_i128), |acc, price| {
+        let price = price as i128;
+        (acc.0 + 1, acc.1 + price, acc.2 + price * price)
+    });
+
+    // Var = (n * Σx² - (Σx)²) / n², and ⌊√x⌋ == ⌊√⌊x⌋⌋ for any x >= 0
+    let variance = (count * sum_of_squares - sum * sum) / (count * count);
+    (variance as u128).isqrt() as i64
+}
+
+fn time_weighted_average(samples: &[(Timestamp, Price)], max_time: Timestamp) -> i64 {
+    let ends = samples
+        .iter()
+        .skip(1)
+        .map(|(ts, _price)| *ts as i128)
+        .chain([max_time as i128 + 1]);
+
+    let (weighted_sum, total_time) =
+        samples
+            .iter()
+            .zip(ends)
+            .fold((0_i128, 0_i128), |acc, ((start, price), end)| {
+                let duration = end - *start as i128;
+                (acc.0 + *price as i128 * duration, acc.1 + duration)
+            });
+
+    weighted_sum.div_euclid(total_time) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Aggregate, AggregateMessage, AggregateResponse, Request, SessionPrices};
+
+    fn session(prices: &[(i32, i32)]) -> SessionPrices {
+        prices.iter().copied().collect()
+    }
+
+    fn aggregate(aggregate: Aggregate, session_prices: &SessionPrices) -> i64 {
+        AggregateMessage::new(aggregate, i32::MIN, i32::MAX)
+            .process(session_prices)
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_every_aggregate_message_to_a_request() {
+        for aggregate in [
+            Aggregate::Min,
+            Aggregate::Max,
+            Aggregate::Median,
+            Aggregate::Count,
+            Aggregate::Sum,
+            Aggregate::StdDev,
+            Aggregate::TimeWeightedAverage,
+        ] {
+            let mut network_data = vec![aggregate.type_byte()];
+            network_data.extend(100_i32.to_be_bytes());
+            network_data.extend(200_i32.to_be_bytes());
+
+            let req = Request::new(&network_data).unwrap();
+
+            assert_eq!(
+                req,
+                Request::Aggregate(AggregateMessage::new(aggregate, 100, 200))
+            );
+        }
+    }
+
+    #[test]
+    fn computes_simple_aggregates() {
+        let session_prices = session(&[(1, 10), (2, -4), (3, 7), (4, 7)]);
+
+        assert_eq!(aggregate(Aggregate::Min, &session_prices), -4);
+        assert_eq!(aggregate(Aggregate::Max, &session_prices), 10);
+        assert_eq!(aggregate(Aggregate::Count, &session_prices), 4);
+        assert_eq!(aggregate(Aggregate::Sum, &session_prices), 20);
+    }
+
+    #[test]
+    fn computes_the_median() {
+        let odd = session(&[(1, 5), (2, 1), (3, 3)]);
+        let even = session(&[(1, 5), (2, 1), (3, 3), (4, 8)]);
+        let negative = session(&[(1, -1), (2, -2)]);
+
+        assert_eq!(aggregate(Aggregate::Median, &odd), 3);
+        assert_eq!(aggregate(Aggregate::Median, &even), 4);
+        // -1.5 is rounded down
+        assert_eq!(aggregate(Aggregate::Median, &negative), -2);
+    }
+
+    #[test]
+    fn computes_the_standard_deviation() {
+        let session_prices = session(&[
+            (1, 2),
+            (2, 4),
+            (3, 4),
+            (4, 4),
+            (5, 5),
+            (6, 5),
+            (7, 7),
+            (8, 9),
+        ]);
+        let extreme = session(&[(1, i32::MIN), (2, i32::MAX)]);
+
+        assert_eq!(aggregate(Aggregate::StdDev, &session_prices), 2);
+        assert_eq!(aggregate(Aggregate::StdDev, &extreme), 2_147_483_647);
+    }
+
+    #[test]
+    fn computes_the_time_weighted_average() {
+        // 10 holds for 9s, 40 for 1s
+        let session_prices = session(&[(100, 10), (109, 40)]);
+
+        let twap = AggregateMessage::new(Aggregate::TimeWeightedAverage, 100, 109)
+            .process(&session_prices)
+            .unwrap();
+
+        assert_eq!(twap, 13);
+    }
+
+    #[test]
+    fn sums_do_not_overflow() {
+        let session_prices = session(&[(1, i32::MAX), (2, i32::MAX), (3, i32::MAX)]);
+
+        assert_eq!(
+            aggregate(Aggregate::Sum, &session_prices),
+            3 * i32::MAX as i64
+        );
+    }
+
+    #[test]
+    fn empty_and_inverted_periods_are_zero() {
+        let session_prices = session(&[(1, 10)]);
+
+        let empty = AggregateMessage::new(Aggregate::Max, 5, 10).process(&session_prices);
+        let inverted = AggregateMessage::new(Aggregate::Count, 10, 0).process(&session_prices);
+
+        assert_eq!(empty, Ok(0));
+        assert_eq!(inverted, Ok(0));
+    }
+
+    #[test]
+    fn converts_an_aggregate_response_to_bytes() {
+        let res = AggregateResponse::new(-2);
+
+        assert_eq!(res.to_bytes(), [255, 255, 255, 255, 255, 255, 255, 254]);
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::{Error, Limit, Limits, ParseError, ProjectResult, SessionPrices};
+
+/// Type byte of the handshake naming an asset.
+pub const ASSET: u8 = b'A';
+
+/// Prices shared by every connection working on the same asset.
+pub type SharedPrices = Arc<RwLock<SessionPrices>>;
+
+/// Name of an asset: 1 to 8 printable ASCII characters. On the wire it takes
+/// 8 bytes, padded at the end with zeros.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct AssetSymbol(String);
+
+/// Handshake moving the connection onto the shared prices of an asset.
+#[derive(Debug, PartialEq)]
+pub struct AssetMessage {
+    symbol: AssetSymbol,
+}
+
+/// Price stores of the named assets, created on first use.
+///
+/// Stores outlive the connections that use them, so a consumer can query the
+/// prices of a producer that already left. With a maximum number of assets,
+/// naming a new asset once it is reached first drops the assets that hold no
+/// prices and are not used by any connection, and fails if none are.
+#[derive(Clone, Default)]
+pub struct AssetRegistry {
+    assets: Arc<Mutex<HashMap<AssetSymbol, SharedPrices>>>,
+    limits: Option<Arc<Limits>>,
+    max_assets: Option<usize>,
+}
+
+impl AssetSymbol {
+    pub fn new(symbol: &str) -> ProjectResult<Self> {
+        let mut bytes = [0; 8];
+        match bytes.get_mut(..symbol.len()) {
+            Some(prefix) => prefix.copy_from_slice(symbol.as_bytes()),
+            None => return Err(ParseError::InvalidSymbol.into()),
+        }
+
+        Self::from_bytes(bytes)
+    }
+
+    /// The symbol padded with zeros, as sent on the wire.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[..self.0.len()].copy_from_slice(self.0.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; 8]) -> ProjectResult<Self> {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        let (symbol, padding) = bytes.split_at(len);
+
+        if symbol.is_empty()
+            || !symbol.iter().all(u8::is_ascii_graphic)
+            || padding.iter().any(|b| *b != 0)
+        {
+            return Err(ParseError::InvalidSymbol.into());
+        }
+
+        // Only ASCII so far
+        Ok(Self(String::from_utf8(symbol.to_vec()).unwrap()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for AssetSymbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl AssetMessage {
+    pub fn new(symbol: AssetSymbol) -> Self {
+        Self { symbol }
+    }
+
+    pub fn symbol(&self) -> &AssetSymbol {
+        &self.symbol
+    }
+}
+
+impl AssetRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the price stores of new assets with `limits`.
+    pub fn with_limits(limits: Arc<Limits>) -> Self {
+        Self {
+            limits: Some(limits),
+            ..Self::default()
+        }
+    }
+
+    /// Caps the number of assets.
+    pub fn with_max_assets(self, max_assets: usize) -> Self {
+        Self {
+            max_assets: Some(max_assets),
+            ..self
+        }
+    }
+
+    /// Prices of `symbol`, empty the first time the asset is named. Fails with
+    /// [`Limit::Assets`] if the asset is new and there is no room for it.
+    pub fn prices(&self, symbol: &AssetSymbol) -> ProjectResult<SharedPrices> {
+        let mut assets = self.assets.lock().unwrap();
+        if let Some(prices) = assets.get(symbol) {
+            return Ok(prices.clone());
+        }
+
+        if let Some(max_assets) = self.max_assets {
+            if assets.len() >= max_assets {
+                // Only the registry holds them, and there is nothing to query
+                assets.retain(|_, prices| {
+                    Arc::strong_count(prices) > 1 || !prices.read().unwrap().is_empty()
+                });
+            }
+            if assets.len() >= max_assets {
+                return Err(Error::LimitExceeded(Limit::Assets));
+            }
+        }
+
+        let prices = match &self.limits {
+            Some(limits) => SessionPrices::with_limits(limits.clone()),
+            None => SessionPrices::new(),
+        };
+        let prices = Arc::new(RwLock::new(prices));
+        assets.insert(symbol.clone(), prices.clone());
+
+        Ok(prices)
+    }
+
+    /// Number of assets named so far.
+    pub fn len(&self) -> usize {
+        self.assets.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::{
+        AssetMessage, AssetRegistry, AssetSymbol, BatchInsertMessage, Error, Limit, ParseError,
+        Request, BATCH_INSERT,
+    };
+
+    fn symbol(name: &str) -> AssetSymbol {
+        AssetSymbol::new(name).unwrap()
+    }
+
+    #[test]
+    fn parses_an_asset_message_to_a_request() {
+        let req = Request::new(b"ABTC\0\0\0\0\0").unwrap();
+
+        assert_eq!(req, Request::Asset(AssetMessage::new(symbol("BTC"))));
+    }
+
+    #[test]
+    fn rejects_invalid_symbols() {
+        for bytes in [*b"\0\0\0\0\0\0\0\0", *b"BT C\0\0\0\0", *b"BTC\0X\0\0\0"] {
+            assert_eq!(
+                AssetSymbol::from_bytes(bytes),
+                Err(Error::Parse(ParseError::InvalidSymbol))
+            );
+        }
+        assert_eq!(
+            AssetSymbol::new("ABCDEFGHI"),
+            Err(Error::Parse(ParseError::InvalidSymbol))
+        );
+        assert_eq!(symbol("ABCDEFGH").as_str(), "ABCDEFGH");
+    }
+
+    #[test]
+    fn connections_share_the_prices_of_an_asset() {
+        // Arrange
+        let registry = AssetRegistry::new();
+        let producer = registry.prices(&symbol("BTC")).unwrap();
+        let consumer = registry.prices(&symbol("BTC")).unwrap();
+        let other = registry.prices(&symbol("ETH")).unwrap();
+
+        // Act
+        producer.write().unwrap().push(1, 100);
+
+        // Assert
+        assert!(Arc::ptr_eq(&producer, &consumer));
+        assert_eq!(consumer.read().unwrap().len(), 1);
+        assert!(other.read().unwrap().is_empty());
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[test]
+    fn concurrent_producers_write_to_one_series() {
+        // Arrange
+        let registry = AssetRegistry::new();
+        let batches: Vec<BatchInsertMessage> = (0..8_i32)
+            .map(|producer| {
+                let mut frame = vec![BATCH_INSERT];
+                frame.extend(100_u32.to_be_bytes());
+                for i in 0..100 {
+                    frame.extend((producer * 1000 + i).to_be_bytes());
+                    frame.extend(producer.to_be_bytes());
+                }
+                match Request::new(&frame).unwrap() {
+                    Request::BatchInsert(batch) => batch,
+                    _ => unreachable!(),
+                }
+            })
+            .collect();
+
+        // Act
+        thread::scope(|s| {
+            for batch in &batches {
+                let prices = registry.prices(&symbol("BTC")).unwrap();
+                s.spawn(move || batch.process(&mut prices.write().unwrap()).unwrap());
+            }
+        });
+
+        // Assert
+        let prices = registry.prices(&symbol("BTC")).unwrap();
+        let prices = prices.read().unwrap();
+        assert_eq!(prices.len(), 800);
+        for producer in 0..8 {
+            assert_eq!(
+                prices.count_and_sum(producer * 1000, producer * 1000 + 999),
+                (100, producer as i64 * 100)
+            );
+        }
+    }
+
+    #[test]
+    fn caps_the_number_of_assets() {
+        // Arrange
+        let registry = AssetRegistry::new().with_max_assets(2);
+        let btc = registry.prices(&symbol("BTC")).unwrap();
+        btc.write().unwrap().push(1, 100);
+        drop(btc);
+        let eth = registry.prices(&symbol("ETH")).unwrap();
+
+        // Act
+        let full = registry.prices(&symbol("SOL"));
+        drop(eth);
+        let after_eth_left = registry.prices(&symbol("SOL"));
+
+        // Assert
+        assert_eq!(full.err(), Some(Error::LimitExceeded(Limit::Assets)));
+        assert!(after_eth_left.is_ok());
+        assert_eq!(registry.len(), 2);
+        // The prices of a producer that left are kept
+        assert_eq!(
+            registry
+                .prices(&symbol("BTC"))
+                .unwrap()
+                .read()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
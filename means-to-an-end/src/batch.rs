@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+
+use crate::{
+    DuplicatePolicy, Error, InsertMessage, ParseError, Price, ProjectResult, QueryMessage,
+    SessionPrices,
+};
+
+/// Type byte of a batch insert message.
+pub const BATCH_INSERT: u8 = b'B';
+/// Type byte of a batch query message.
+pub const BATCH_QUERY: u8 = b'P';
+/// Largest number of entries in a batch. Bigger batches are rejected before
+/// reading them, so a bogus count cannot make the server buffer gigabytes.
+pub const MAX_BATCH_LEN: u32 = 65_536;
+
+/// Size of the batch header: the type byte and a big-endian `u32` count.
+pub(crate) const BATCH_HEADER_LEN: usize = 5;
+/// Size of every batch entry: two big-endian `i32`s, like the payload of `I`
+/// and `Q` messages.
+const ENTRY_LEN: usize = 8;
+
+/// Several inserts applied as one: either every price is stored or none is.
+#[derive(Debug, PartialEq)]
+pub struct BatchInsertMessage {
+    inserts: Vec<InsertMessage>,
+}
+
+/// Several queries answered with a single [`BatchResponse`].
+#[derive(Debug, PartialEq)]
+pub struct BatchQueryMessage {
+    queries: Vec<QueryMessage>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchResponse {
+    /// Mean of every query, in the order of the queries.
+    means: Vec<Price>,
+}
+
+/// Size of the batch frame starting with `bytes`, or `None` until the whole
+/// header is available.
+pub(crate) fn frame_len(bytes: &[u8]) -> ProjectResult<Option<usize>> {
+    let Some(header) = bytes.get(..BATCH_HEADER_LEN) else {
+        return Ok(None);
+    };
+
+    let count = u32::from_be_bytes(header[1..].try_into().unwrap());
+    if count > MAX_BATCH_LEN {
+        return Err(ParseError::BatchTooLarge(count).into());
+    }
+
+    Ok(Some(BATCH_HEADER_LEN + count as usize * ENTRY_LEN))
+}
+
+fn check_len(len: usize) -> ProjectResult<()> {
+    match u32::try_from(len) {
+        Ok(count) if count <= MAX_BATCH_LEN => Ok(()),
+        _ => Err(ParseError::BatchTooLarge(len.try_into().unwrap_or(u32::MAX)).into()),
+    }
+}
+
+/// Writes a batch frame with the given entries.
+fn to_frame(type_byte: u8, entries: impl ExactSizeIterator<Item = (i32, i32)>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(BATCH_HEADER_LEN + entries.len() * ENTRY_LEN);
+    bytes.push(type_byte);
+    bytes.extend((entries.len() as u32).to_be_bytes());
+    for (a, b) in entries {
+        bytes.extend(a.to_be_bytes());
+        bytes.extend(b.to_be_bytes());
+    }
+    bytes
+}
+
+/// Splits a batch frame into its `(i32, i32)` entries.
+fn entries(bytes: &[u8]) -> ProjectResult<Vec<(i32, i32)>> {
+    match frame_len(bytes)? {
+        Some(len) if len == bytes.len() => {}
+        _ => return Err(ParseError::InvalidLength.into()),
+    }
+
+    let entries = bytes[BATCH_HEADER_LEN..]
+        .chunks_exact(ENTRY_LEN)
+        .map(|entry| {
+            (
+                i32::from_be_bytes(entry[..4].try_into().unwrap()),
+                i32::from_be_bytes(entry[4..].try_into().unwrap()),
+            )
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+impl BatchInsertMessage {
+    /// Fails if there are more than [`MAX_BATCH_LEN`] inserts.
+    pub fn new(inserts: Vec<InsertMessage>) -> ProjectResult<Self> {
+        check_len(inserts.len())?;
+        Ok(Self { inserts })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        to_frame(
+            BATCH_INSERT,
+            self.inserts.iter().map(|i| (i.timestamp, i.price)),
+        )
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> ProjectResult<Self> {
+        let inserts = entries(bytes)?
+            .into_iter()
+            .map(|(timestamp, price)| InsertMessage { timestamp, price })
+            .collect();
+
+        Ok(Self { inserts })
+    }
+
+    pub fn inserts(&self) -> &[InsertMessage] {
+        &self.inserts
+    }
+
+    /// Inserts every price, rejecting the whole batch if any timestamp is a
+    /// duplicate.
+    pub fn process(&self, session_prices: &mut SessionPrices) -> ProjectResult<()> {
+        self.process_with_policy(session_prices, DuplicatePolicy::Reject)
+    }
+
+    /// Inserts every price with `policy`. Duplicates inside the batch count
+    /// too, so the result is the same as sending the inserts one by one,
+    /// except that a rejected batch leaves `session_prices` untouched.
+    pub fn process_with_policy(
+        &self,
+        session_prices: &mut SessionPrices,
+        policy: DuplicatePolicy,
+    ) -> ProjectResult<()> {
+        // Duplicates and limits are checked before touching the session, as
+        // applying the inserts cannot fail afterwards
+        let mut seen = HashSet::with_capacity(self.inserts.len());
+        let mut additions = Vec::with_capacity(self.inserts.len());
+        for insert in &self.inserts {
+            let is_duplicate =
+                session_prices.contains(insert.timestamp) || !seen.insert(insert.timestamp);
+            match policy {
+                DuplicatePolicy::Reject if is_duplicate => {
+                    return Err(Error::DuplicateTimestamp(insert.timestamp))
+                }
+                DuplicatePolicy::KeepBoth => additions.push(insert.timestamp),
+                _ if !is_duplicate => additions.push(insert.timestamp),
+                _ => {}
+            }
+        }
+        session_prices.check_limits(&additions)?;
+
+        for insert in &self.inserts {
+            insert.apply(session_prices, policy)?;
+        }
+        session_prices.enforce_limits();
+
+        Ok(())
+    }
+}
+
+impl BatchQueryMessage {
+    /// Fails if there are more than [`MAX_BATCH_LEN`] queries.
+    pub fn new(queries: Vec<QueryMessage>) -> ProjectResult<Self> {
+        check_len(queries.len())?;
+        Ok(Self { queries })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        to_frame(
+            BATCH_QUERY,
+            self.queries.iter().map(|q| (q.min_time, q.max_time)),
+        )
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> ProjectResult<Self> {
+        let queries = entries(bytes)?
+            .into_iter()
+            .map(|(min_time, max_time)| QueryMessage { min_time, max_time })
+            .collect();
+
+        Ok(Self { queries })
+    }
+
+    pub fn queries(&self) -> &[QueryMessage] {
+        &self.queries
+    }
+
+    /// Answers every query, failing if any of them fails.
+    pub fn process(&self, session_prices: &SessionPrices) -> ProjectResult<BatchResponse> {
+        let means = self
+            .queries
+            .iter()
+            .map(|query| query.process(session_prices))
+            .collect::<ProjectResult<_>>()?;
+
+        Ok(BatchResponse { means })
+    }
+}
+
+impl BatchResponse {
+    pub fn new(means: Vec<Price>) -> Self {
+        Self { means }
+    }
+
+    pub fn means(&self) -> &[Price] {
+        &self.means
+    }
+
+    /// The number of means as a big-endian `u32`, followed by every mean as a
+    /// big-endian `i32`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.means.len() * 4);
+        bytes.extend((self.means.len() as u32).to_be_bytes());
+        for mean in &self.means {
+            bytes.extend(mean.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> ProjectResult<Self> {
+        let Some((count, means)) = bytes.split_first_chunk::<4>() else {
+            return Err(ParseError::InvalidLength.into());
+        };
+        if means.len() != u32::from_be_bytes(*count) as usize * 4 {
+            return Err(ParseError::InvalidLength.into());
+        }
+
+        let means = means
+            .chunks_exact(4)
+            .map(|mean| Price::from_be_bytes(mean.try_into().unwrap()))
+            .collect();
+
+        Ok(Self { means })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    use crate::{
+        BatchInsertMessage, DuplicatePolicy, Error, MeansCodec, ParseError, Request, SessionPrices,
+        BATCH_INSERT, BATCH_QUERY, MAX_BATCH_LEN,
+    };
+
+    fn frame(type_byte: u8, entries: &[(i32, i32)]) -> Vec<u8> {
+        let mut frame = vec![type_byte];
+        frame.extend((entries.len() as u32).to_be_bytes());
+        for (a, b) in entries {
+            frame.extend(a.to_be_bytes());
+            frame.extend(b.to_be_bytes());
+        }
+        frame
+    }
+
+    fn batch_insert(entries: &[(i32, i32)]) -> BatchInsertMessage {
+        match Request::new(&frame(BATCH_INSERT, entries)).unwrap() {
+            Request::BatchInsert(message) => message,
+            other => panic!("unexpected request: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_batch_messages() {
+        let insert = Request::new(&frame(BATCH_INSERT, &[(1, 10), (2, 20)])).unwrap();
+        let query = Request::new(&frame(BATCH_QUERY, &[(0, 5)])).unwrap();
+        let empty = Request::new(&frame(BATCH_QUERY, &[])).unwrap();
+
+        assert!(matches!(insert, Request::BatchInsert(m) if m.inserts().len() == 2));
+        assert!(matches!(query, Request::BatchQuery(m) if m.queries().len() == 1));
+        assert!(matches!(empty, Request::BatchQuery(m) if m.queries().is_empty()));
+    }
+
+    #[test]
+    fn rejects_a_count_that_does_not_match_the_entries() {
+        let mut bytes = frame(BATCH_INSERT, &[(1, 10), (2, 20)]);
+        bytes.truncate(bytes.len() - 8);
+
+        assert_eq!(
+            Request::new(&bytes),
+            Err(Error::Parse(ParseError::InvalidLength))
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_batches() {
+        let mut bytes = vec![BATCH_INSERT];
+        bytes.extend((MAX_BATCH_LEN + 1).to_be_bytes());
+
+        assert_eq!(
+            Request::new(&bytes),
+            Err(Error::Parse(ParseError::BatchTooLarge(MAX_BATCH_LEN + 1)))
+        );
+    }
+
+    #[test]
+    fn decodes_a_batch_once_every_entry_arrived() {
+        // Arrange
+        let mut codec = MeansCodec::new();
+        let bytes = frame(BATCH_INSERT, &[(1, 10), (2, 20)]);
+        let mut buffer = BytesMut::new();
+
+        // Act
+        let decoded: Vec<_> = bytes
+            .iter()
+            .map(|byte| {
+                buffer.extend_from_slice(&[*byte]);
+                codec.decode(&mut buffer).unwrap()
+            })
+            .collect();
+
+        // Assert
+        let (last, partial) = decoded.split_last().unwrap();
+        assert!(partial.iter().all(Option::is_none));
+        assert!(matches!(last, Some(Ok(Request::BatchInsert(m))) if m.inserts().len() == 2));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn inserts_and_queries_a_batch() {
+        // Arrange
+        let mut session_prices = SessionPrices::new();
+        let inserts = batch_insert(&[(12345, 101), (12346, 102), (12347, 100), (40960, 5)]);
+        let Request::BatchQuery(queries) =
+            Request::new(&frame(BATCH_QUERY, &[(12288, 16384), (0, 50000), (1, 0)])).unwrap()
+        else {
+            unreachable!()
+        };
+
+        // Act
+        inserts.process(&mut session_prices).unwrap();
+        let response = queries.process(&session_prices).unwrap();
+
+        // Assert
+        assert_eq!(response.means(), [101, 77, 0]);
+        assert_eq!(
+            response.to_bytes(),
+            [
+                &3_u32.to_be_bytes()[..],
+                &[0, 0, 0, 101, 0, 0, 0, 77, 0, 0, 0, 0]
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn a_rejected_batch_leaves_the_session_untouched() {
+        // Arrange
+        let mut session_prices: SessionPrices = [(2, 20)].into_iter().collect();
+        let clashes_with_session = batch_insert(&[(1, 10), (2, 21)]);
+        let clashes_with_itself = batch_insert(&[(3, 30), (3, 31)]);
+
+        // Act
+        let first = clashes_with_session.process(&mut session_prices);
+        let second = clashes_with_itself.process(&mut session_prices);
+
+        // Assert
+        assert_eq!(first, Err(Error::DuplicateTimestamp(2)));
+        assert_eq!(second, Err(Error::DuplicateTimestamp(3)));
+        assert_eq!(session_prices.iter().collect::<Vec<_>>(), vec![(2, 20)]);
+    }
+
+    #[test]
+    fn applies_the_duplicate_policy_inside_the_batch() {
+        let mut session_prices = SessionPrices::new();
+
+        batch_insert(&[(1, 10), (1, 20), (2, 5)])
+            .process_with_policy(&mut session_prices, DuplicatePolicy::Overwrite)
+            .unwrap();
+
+        assert_eq!(
+            session_prices.iter().collect::<Vec<_>>(),
+            vec![(1, 20), (2, 5)]
+        );
+    }
+}
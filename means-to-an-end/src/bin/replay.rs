@@ -0,0 +1,104 @@
+//! Replays a recorded stream of frames against the library and prints the
+//! answer to every query, to reproduce what the server sent.
+//!
+//! Usage: `replay <recording> [--policy <duplicate policy>] [--export csv|json]`
+//!
+//! The server settings are read from the environment, like the server does,
+//! so that limits and error frames match. `--policy` overrides
+//! `DUPLICATE_POLICY`. The replay stops where the server would have dropped
+//! the connection.
+//!
+//! With `--export`, the prices of the session at the end of the recording are
+//! printed as well.
+
+use std::{env, fs, io, process::ExitCode};
+
+use means_to_an_end::{export, frames, Config, DuplicatePolicy, ExportFormat, Replay};
+
+struct Args {
+    recording: String,
+    policy: Option<DuplicatePolicy>,
+    export: Option<ExportFormat>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+    let mut recording = None;
+    let mut policy = None;
+    let mut export = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--policy" => {
+                let value = args.next().ok_or("--policy needs a value")?;
+                policy = Some(value.parse()?);
+            }
+            "--export" => {
+                let value = args.next().ok_or("--export needs a value")?;
+                export = Some(value.parse()?);
+            }
+            _ if recording.is_none() => recording = Some(arg),
+            _ => return Err(format!("Unexpected argument: {arg}")),
+        }
+    }
+
+    Ok(Args {
+        recording: recording.ok_or("Missing the recording to replay")?,
+        policy,
+        export,
+    })
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("Usage: replay <recording> [--policy <policy>] [--export csv|json]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let stream = match fs::read(&args.recording) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", args.recording, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut config = Config::from_env();
+    if let Some(policy) = args.policy {
+        config.duplicate_policy = policy;
+    }
+    let mut replay = Replay::new(&config);
+    let mut status = ExitCode::SUCCESS;
+    for (offset, request) in frames(&stream) {
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("{offset}: {e}. Stopping the replay");
+                status = ExitCode::FAILURE;
+                break;
+            }
+        };
+
+        match replay.replay(&request) {
+            Ok(Some(answer)) => println!("{offset}: {request:?} -> {answer}"),
+            Ok(None) => {}
+            Err(e) => {
+                println!("{offset}: {request:?} failed: {e}. The server dropped the connection");
+                break;
+            }
+        }
+    }
+
+    if let Some(format) = args.export {
+        if let Err(e) = export(replay.session_prices(), format, io::stdout().lock()) {
+            eprintln!("Cannot export the session: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    status
+}
@@ -0,0 +1,296 @@
+use crate::{ParseError, Price, ProjectResult, SessionPrices, Timestamp};
+
+/// Type byte of a candles message.
+pub const CANDLES: u8 = b'K';
+/// Size of a candles frame: the type byte, the period as two big-endian
+/// `i32`s and the bucket width as a big-endian `u32`.
+pub const CANDLES_LEN: usize = 13;
+/// Size of an encoded [`Candle`].
+const CANDLE_LEN: usize = 24;
+
+/// Asks for the OHLC candles of a period, one per bucket of `width` seconds.
+/// Buckets start at `min_time`, and the last one is cut at `max_time`.
+#[derive(Debug, PartialEq)]
+pub struct CandlesMessage {
+    /// Earliest timestamp of the period.
+    min_time: Timestamp,
+    /// Latest timestamp of the period.
+    max_time: Timestamp,
+    /// Width of every bucket, in seconds. Never 0.
+    width: u32,
+}
+
+/// Prices of one bucket. Prices sharing a timestamp are ordered as they were
+/// inserted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candle {
+    /// First timestamp of the bucket.
+    pub start: Timestamp,
+    pub open: Price,
+    pub high: Price,
+    pub low: Price,
+    pub close: Price,
+    /// Number of prices in the bucket.
+    pub count: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CandlesResponse {
+    /// Candles of the buckets holding at least one price, in time order.
+    candles: Vec<Candle>,
+}
+
+impl CandlesMessage {
+    /// Fails if `width` is 0.
+    pub fn new(min_time: Timestamp, max_time: Timestamp, width: u32) -> ProjectResult<Self> {
+        if width == 0 {
+            return Err(ParseError::InvalidBucketWidth.into());
+        }
+
+        Ok(Self {
+            min_time,
+            max_time,
+            width,
+        })
+    }
+
+    pub fn min_time(&self) -> Timestamp {
+        self.min_time
+    }
+
+    pub fn max_time(&self) -> Timestamp {
+        self.max_time
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn to_bytes(&self) -> [u8; CANDLES_LEN] {
+        let mut bytes = [0; CANDLES_LEN];
+        bytes[0] = CANDLES;
+        bytes[1..5].copy_from_slice(&self.min_time.to_be_bytes());
+        bytes[5..9].copy_from_slice(&self.max_time.to_be_bytes());
+        bytes[9..].copy_from_slice(&self.width.to_be_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> ProjectResult<Self> {
+        let bytes: [u8; CANDLES_LEN] = bytes.try_into().map_err(|_| ParseError::InvalidLength)?;
+        let field = |i: usize| bytes[i..i + 4].try_into().unwrap();
+
+        Self::new(
+            i32::from_be_bytes(field(1)),
+            i32::from_be_bytes(field(5)),
+            u32::from_be_bytes(field(9)),
+        )
+    }
+
+    /// Candles of the period. Empty buckets are left out, so the response
+    /// never has more candles than the session has prices.
+    pub fn process(&self, session_prices: &SessionPrices) -> CandlesResponse {
+        let mut candles: Vec<Candle> = Vec::new();
+        if self.min_time > self.max_time {
+            return CandlesResponse { candles };
+        }
+
+        for (timestamp, price) in session_prices.range(self.min_time..=self.max_time) {
+            let start = self.bucket_start(timestamp);
+            match candles.last_mut() {
+                Some(candle) if candle.start == start => {
+                    candle.high = candle.high.max(price);
+                    candle.low = candle.low.min(price);
+                    candle.close = price;
+                    candle.count += 1;
+                }
+                _ => candles.push(Candle {
+                    start,
+                    open: price,
+                    high: price,
+                    low: price,
+                    close: price,
+                    count: 1,
+                }),
+            }
+        }
+
+        CandlesResponse { candles }
+    }
+
+    fn bucket_start(&self, timestamp: Timestamp) -> Timestamp {
+        let offset = timestamp as i64 - self.min_time as i64;
+        // Between `min_time` and `timestamp`, so it fits
+        (self.min_time as i64 + offset - offset % self.width as i64) as Timestamp
+    }
+}
+
+impl Candle {
+    fn to_bytes(self) -> [u8; CANDLE_LEN] {
+        let mut bytes = [0; CANDLE_LEN];
+        let fields = [self.start, self.open, self.high, self.low, self.close];
+        for (i, field) in fields.iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&field.to_be_bytes());
+        }
+        bytes[20..].copy_from_slice(&self.count.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let field = |i: usize| bytes[i * 4..i * 4 + 4].try_into().unwrap();
+
+        Self {
+            start: i32::from_be_bytes(field(0)),
+            open: i32::from_be_bytes(field(1)),
+            high: i32::from_be_bytes(field(2)),
+            low: i32::from_be_bytes(field(3)),
+            close: i32::from_be_bytes(field(4)),
+            count: u32::from_be_bytes(field(5)),
+        }
+    }
+}
+
+impl CandlesResponse {
+    pub fn new(candles: Vec<Candle>) -> Self {
+        Self { candles }
+    }
+
+    pub fn candles(&self) -> &[Candle] {
+        &self.candles
+    }
+
+    /// The number of candles as a big-endian `u32`, followed by every candle
+    /// as its start, open, high, low and close as big-endian `i32`s, and its
+    /// count as a big-endian `u32`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.candles.len() * CANDLE_LEN);
+        bytes.extend((self.candles.len() as u32).to_be_bytes());
+        for candle in &self.candles {
+            bytes.extend(candle.to_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> ProjectResult<Self> {
+        let Some((count, candles)) = bytes.split_first_chunk::<4>() else {
+            return Err(ParseError::InvalidLength.into());
+        };
+        if candles.len() != u32::from_be_bytes(*count) as usize * CANDLE_LEN {
+            return Err(ParseError::InvalidLength.into());
+        }
+
+        let candles = candles
+            .chunks_exact(CANDLE_LEN)
+            .map(Candle::from_bytes)
+            .collect();
+
+        Ok(Self { candles })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Candle, CandlesMessage, CandlesResponse, Error, ParseError, Request, SessionPrices,
+    };
+
+    fn candles(prices: &[(i32, i32)], min_time: i32, max_time: i32, width: u32) -> Vec<Candle> {
+        let session_prices: SessionPrices = prices.iter().copied().collect();
+
+        CandlesMessage::new(min_time, max_time, width)
+            .unwrap()
+            .process(&session_prices)
+            .candles()
+            .to_vec()
+    }
+
+    fn candle(start: i32, ohlc: [i32; 4], count: u32) -> Candle {
+        let [open, high, low, close] = ohlc;
+        Candle {
+            start,
+            open,
+            high,
+            low,
+            close,
+            count,
+        }
+    }
+
+    #[test]
+    fn parses_a_candles_message_to_a_request() {
+        let message = CandlesMessage::new(-100, 200, 60).unwrap();
+
+        let req = Request::new(&message.to_bytes()).unwrap();
+
+        assert_eq!(req, Request::Candles(message));
+        assert_eq!(
+            Request::new(&CandlesMessage::new(0, 1, 1).unwrap().to_bytes()[..9]),
+            Err(Error::Parse(ParseError::InvalidLength))
+        );
+    }
+
+    #[test]
+    fn rejects_an_empty_bucket_width() {
+        let mut bytes = CandlesMessage::new(0, 1, 1).unwrap().to_bytes();
+        bytes[12] = 0;
+
+        assert_eq!(
+            Request::new(&bytes),
+            Err(Error::Parse(ParseError::InvalidBucketWidth))
+        );
+    }
+
+    #[test]
+    fn computes_a_candle_per_bucket() {
+        let prices = [
+            (0, 5),
+            (3, 8),
+            (9, 2),
+            (10, 7),
+            (15, 9),
+            (19, 1),
+            (19, 4),
+            (35, 6),
+            (40, 100),
+        ];
+
+        let candles = candles(&prices, 0, 39, 10);
+
+        assert_eq!(
+            candles,
+            vec![
+                candle(0, [5, 8, 2, 2], 3),
+                candle(10, [7, 9, 1, 4], 4),
+                candle(30, [6, 6, 6, 6], 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn aligns_buckets_on_the_start_of_the_period() {
+        let prices = [(-7, 1), (-2, 2), (4, 3), (i32::MAX, 4)];
+
+        assert_eq!(
+            candles(&prices, -5, i32::MAX, 5),
+            vec![
+                candle(-5, [2, 2, 2, 2], 1),
+                candle(0, [3, 3, 3, 3], 1),
+                candle(i32::MAX - 2, [4, 4, 4, 4], 1),
+            ]
+        );
+        assert_eq!(candles(&prices, 5, -5, 5), vec![]);
+    }
+
+    #[test]
+    fn responses_round_trip_through_bytes() {
+        let response = CandlesResponse::new(vec![
+            candle(0, [5, 8, 2, 2], 3),
+            candle(-10, [i32::MIN, i32::MAX, -1, 0], u32::MAX),
+        ]);
+
+        let bytes = response.to_bytes();
+
+        assert_eq!(bytes.len(), 4 + 2 * 24);
+        assert_eq!(&bytes[..8], [0, 0, 0, 2, 0, 0, 0, 0]);
+        assert_eq!(CandlesResponse::from_bytes(&bytes), Ok(response));
+    }
+}
@@ -0,0 +1,77 @@
+use std::{env, path::PathBuf, str::FromStr};
+
+use crate::{DuplicatePolicy, ExportFormat, Retention};
+
+const DEFAULT_MAX_ASSETS: usize = 1024;
+
+/// Server settings, shared by every connection.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    /// Directory holding the price logs of identified clients. `None`
+    /// disables identification.
+    pub data_dir: Option<PathBuf>,
+    pub duplicate_policy: DuplicatePolicy,
+    /// Tag results with [`crate::RESULT_FRAME`] and report errors with
+    /// [`crate::ERROR_FRAME`] frames instead of silently disconnecting.
+    pub error_frames: bool,
+    /// Let clients share the prices of a named asset with
+    /// [`crate::AssetMessage`] handshakes.
+    pub named_assets: bool,
+    /// Most assets kept at once. `None` leaves them unbounded.
+    pub max_assets: Option<usize>,
+    /// Bounds on the prices kept in memory.
+    pub retention: Retention,
+    /// Directory where the prices of every session are exported when its
+    /// connection closes. `None` disables the exports.
+    pub export_dir: Option<PathBuf>,
+    pub export_format: ExportFormat,
+    /// Accept admin commands, such as [`crate::ExportMessage`], from clients.
+    pub admin_commands: bool,
+}
+
+impl Config {
+    /// Reads the configuration from the environment, falling back to the
+    /// defaults for missing or unparsable values.
+    ///
+    /// - `DATA_DIR`
+    /// - `DUPLICATE_POLICY` (`reject`, `ignore`, `overwrite`, `keep-both` or
+    ///   `average`)
+    /// - `ERROR_FRAMES` (`1` or `true` to enable)
+    /// - `NAMED_ASSETS` (`1` or `true` to enable)
+    /// - `MAX_ASSETS` (1024 by default)
+    /// - `MAX_PRICE_AGE` (seconds behind the newest price of a session)
+    /// - `MAX_SESSION_PRICES`
+    /// - `MAX_TOTAL_PRICES`
+    /// - `LIMIT_POLICY` (`evict` or `reject`)
+    /// - `EXPORT_DIR`
+    /// - `EXPORT_FORMAT` (`csv` or `json`)
+    /// - `ADMIN_COMMANDS` (`1` or `true` to enable)
+    pub fn from_env() -> Self {
+        Self {
+            data_dir: env::var("DATA_DIR").ok().map(PathBuf::from),
+            duplicate_policy: env_parse("DUPLICATE_POLICY").unwrap_or_default(),
+            error_frames: env_flag("ERROR_FRAMES"),
+            named_assets: env_flag("NAMED_ASSETS"),
+            max_assets: Some(env_parse("MAX_ASSETS").unwrap_or(DEFAULT_MAX_ASSETS)),
+            retention: Retention {
+                max_age: env_parse("MAX_PRICE_AGE"),
+                max_session_prices: env_parse("MAX_SESSION_PRICES"),
+                max_total_prices: env_parse("MAX_TOTAL_PRICES"),
+                policy: env_parse("LIMIT_POLICY").unwrap_or_default(),
+            },
+            export_dir: env::var("EXPORT_DIR").ok().map(PathBuf::from),
+            export_format: env_parse("EXPORT_FORMAT").unwrap_or_default(),
+            admin_commands: env_flag("ADMIN_COMMANDS"),
+        }
+    }
+}
+
+fn env_flag(key: &str) -> bool {
+    env::var(key)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+fn env_parse<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|v| v.parse().ok())
+}
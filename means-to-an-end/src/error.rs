@@ -0,0 +1,111 @@
+use std::fmt::Display;
+
+use crate::{Limit, Timestamp};
+
+/// First byte of a frame carrying a result, when error frames are enabled.
+pub const RESULT_FRAME: u8 = b'R';
+/// First byte of a frame carrying an [`Error`], when error frames are enabled.
+pub const ERROR_FRAME: u8 = b'E';
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseError {
+    /// The first byte does not match any message type.
+    UnknownMessageType(u8),
+    /// The frame is too short or too long for its message type.
+    InvalidLength,
+    /// A batch announces more entries than [`crate::MAX_BATCH_LEN`].
+    BatchTooLarge(u32),
+    /// An asset symbol is empty, not printable ASCII or badly padded.
+    InvalidSymbol,
+    /// An export command names an unknown format or is badly padded.
+    InvalidExportFormat,
+    /// A candles message asks for buckets of width 0.
+    InvalidBucketWidth,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The client sent bytes that are not a valid message.
+    Parse(ParseError),
+    /// An insert reused a timestamp and the server rejects duplicates.
+    DuplicateTimestamp(Timestamp),
+    /// A result does not fit in its response.
+    Overflow,
+    /// The message is valid but not allowed at this point of the session.
+    Protocol(&'static str),
+    /// An insert would break a retention limit and the server rejects it.
+    LimitExceeded(Limit),
+}
+
+impl Error {
+    /// Code sent to the client in an error frame.
+    pub fn code(&self) -> u8 {
+        match self {
+            Error::Parse(_) => 1,
+            Error::DuplicateTimestamp(_) => 2,
+            Error::Overflow => 3,
+            Error::Protocol(_) => 4,
+            Error::LimitExceeded(_) => 5,
+        }
+    }
+
+    /// Whether the session can go on after the error. After a parse error the
+    /// server cannot trust the framing of the rest of the stream.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, Error::Parse(_))
+    }
+
+    /// Error frame sent to clients that enabled the extension: the
+    /// [`ERROR_FRAME`] byte followed by the error code.
+    pub fn to_frame(&self) -> [u8; 2] {
+        [ERROR_FRAME, self.code()]
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(ParseError::UnknownMessageType(t)) => {
+                write!(f, "unknown message type: {:?}", *t as char)
+            }
+            Error::Parse(ParseError::InvalidLength) => write!(f, "invalid message length"),
+            Error::Parse(ParseError::BatchTooLarge(count)) => {
+                write!(f, "batch of {count} entries is too large")
+            }
+            Error::Parse(ParseError::InvalidSymbol) => write!(f, "invalid asset symbol"),
+            Error::Parse(ParseError::InvalidExportFormat) => write!(f, "invalid export format"),
+            Error::Parse(ParseError::InvalidBucketWidth) => write!(f, "invalid bucket width"),
+            Error::DuplicateTimestamp(ts) => write!(f, "timestamp {ts} already exists"),
+            Error::Overflow => write!(f, "result does not fit in the response"),
+            Error::Protocol(reason) => write!(f, "{reason}"),
+            Error::LimitExceeded(limit) => write!(f, "limit exceeded: {limit:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, ParseError};
+
+    #[test]
+    fn converts_an_error_to_a_frame() {
+        let e = Error::DuplicateTimestamp(5);
+
+        assert_eq!(e.to_frame(), [b'E', 2]);
+    }
+
+    #[test]
+    fn only_parse_errors_end_the_session() {
+        assert!(!Error::Parse(ParseError::InvalidLength).is_recoverable());
+        assert!(Error::DuplicateTimestamp(5).is_recoverable());
+        assert!(Error::Overflow.is_recoverable());
+    }
+}
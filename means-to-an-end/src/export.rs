@@ -0,0 +1,284 @@
+use std::{
+    io::{self, BufRead, Write},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ParseError, Price, ProjectResult, SessionPrices, Timestamp};
+
+/// Type byte of the admin command exporting the prices of the session.
+pub const EXPORT: u8 = b'O';
+
+/// Text format of exported prices.
+///
+/// - CSV: a `timestamp,price` header, then one price per line.
+/// - JSON: an array of `{"timestamp": .., "price": ..}` objects.
+///
+/// Prices are written in timestamp order. Prices sharing a timestamp keep
+/// their insertion order, so importing an export gives back the same session.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+/// Admin command asking for the prices of the session: the [`EXPORT`] byte,
+/// the format byte (`C` or `J`) and 7 zero bytes.
+#[derive(Debug, PartialEq)]
+pub struct ExportMessage {
+    format: ExportFormat,
+}
+
+pub struct ExportResponse {
+    /// The exported prices, in the requested format.
+    data: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Record {
+    timestamp: Timestamp,
+    price: Price,
+}
+
+const CSV_HEADER: &str = "timestamp,price";
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+
+    fn type_byte(&self) -> u8 {
+        match self {
+            ExportFormat::Csv => b'C',
+            ExportFormat::Json => b'J',
+        }
+    }
+
+    fn from_type_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'C' => Some(ExportFormat::Csv),
+            b'J' => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err("Unknown export format"),
+        }
+    }
+}
+
+impl ExportMessage {
+    pub fn new(format: ExportFormat) -> Self {
+        Self { format }
+    }
+
+    pub fn format(&self) -> ExportFormat {
+        self.format
+    }
+
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut payload = [0; 8];
+        payload[0] = self.format.type_byte();
+        payload
+    }
+
+    pub(crate) fn from_bytes(payload: [u8; 8]) -> ProjectResult<Self> {
+        let (format, padding) = payload.split_first().unwrap();
+        match ExportFormat::from_type_byte(*format) {
+            Some(format) if padding.iter().all(|b| *b == 0) => Ok(Self { format }),
+            _ => Err(ParseError::InvalidExportFormat.into()),
+        }
+    }
+
+    pub fn process(&self, session_prices: &SessionPrices) -> ExportResponse {
+        let mut data = Vec::new();
+        // Writing to a Vec cannot fail
+        export(session_prices, self.format, &mut data).unwrap();
+
+        ExportResponse { data }
+    }
+}
+
+impl ExportResponse {
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The length of the data as a big-endian `u32`, followed by the data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.data.len());
+        bytes.extend((self.data.len() as u32).to_be_bytes());
+        bytes.extend(&self.data);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> ProjectResult<Self> {
+        match bytes.split_first_chunk::<4>() {
+            Some((len, data)) if u32::from_be_bytes(*len) as usize == data.len() => Ok(Self {
+                data: data.to_vec(),
+            }),
+            _ => Err(ParseError::InvalidLength.into()),
+        }
+    }
+}
+
+/// Writes every price of the session to `writer`.
+pub fn export<W: Write>(
+    session_prices: &SessionPrices,
+    format: ExportFormat,
+    mut writer: W,
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            writeln!(writer, "{CSV_HEADER}")?;
+            for (timestamp, price) in session_prices.iter() {
+                writeln!(writer, "{timestamp},{price}")?;
+            }
+        }
+        ExportFormat::Json => {
+            let records = session_prices
+                .iter()
+                .map(|(timestamp, price)| Record { timestamp, price })
+                .collect::<Vec<_>>();
+            serde_json::to_writer(&mut writer, &records)?;
+            writeln!(writer)?;
+        }
+    }
+
+    writer.flush()
+}
+
+/// Reads prices written by [`export`] back into a session.
+pub fn import<R: BufRead>(format: ExportFormat, reader: R) -> io::Result<SessionPrices> {
+    let invalid = |line: usize, reason: &str| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("line {line}: {reason}"))
+    };
+
+    match format {
+        ExportFormat::Csv => {
+            let mut session_prices = SessionPrices::new();
+            for (i, line) in reader.lines().enumerate() {
+                let line = line?;
+                if (i == 0 && line == CSV_HEADER) || line.is_empty() {
+                    continue;
+                }
+
+                let (timestamp, price) = line
+                    .split_once(',')
+                    .ok_or_else(|| invalid(i + 1, "expected two columns"))?;
+                let timestamp = timestamp
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid(i + 1, "invalid timestamp"))?;
+                let price = price
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid(i + 1, "invalid price"))?;
+                session_prices.push(timestamp, price);
+            }
+
+            Ok(session_prices)
+        }
+        ExportFormat::Json => {
+            let records: Vec<Record> = serde_json::from_reader(reader)?;
+
+            Ok(records
+                .into_iter()
+                .map(|record| (record.timestamp, record.price))
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        export, import, Error, ExportFormat, ExportMessage, ExportResponse, ParseError, Request,
+        SessionPrices,
+    };
+
+    fn session() -> SessionPrices {
+        [(3, 30), (1, -10), (3, 31)].into_iter().collect()
+    }
+
+    fn exported(format: ExportFormat) -> String {
+        let mut data = Vec::new();
+        export(&session(), format, &mut data).unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    #[test]
+    fn exports_csv() {
+        assert_eq!(
+            exported(ExportFormat::Csv),
+            "timestamp,price\n1,-10\n3,30\n3,31\n"
+        );
+    }
+
+    #[test]
+    fn exports_json() {
+        assert_eq!(
+            exported(ExportFormat::Json),
+            "[{\"timestamp\":1,\"price\":-10},{\"timestamp\":3,\"price\":30},{\"timestamp\":3,\"price\":31}]\n"
+        );
+    }
+
+    #[test]
+    fn imports_what_it_exports() {
+        for format in [ExportFormat::Csv, ExportFormat::Json] {
+            let data = exported(format);
+
+            let imported = import(format, Cursor::new(data)).unwrap();
+
+            assert_eq!(
+                imported.iter().collect::<Vec<_>>(),
+                session().iter().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn reports_the_line_of_invalid_csv() {
+        let data = "timestamp,price\n1,10\n2;20\n";
+
+        let e = import(ExportFormat::Csv, Cursor::new(data)).unwrap_err();
+
+        assert_eq!(e.to_string(), "line 3: expected two columns");
+    }
+
+    #[test]
+    fn serves_an_export_command() {
+        // Arrange
+        let mut frame = vec![b'O'];
+        frame.extend(ExportMessage::new(ExportFormat::Csv).to_bytes());
+
+        // Act
+        let Request::Export(message) = Request::new(&frame).unwrap() else {
+            unreachable!()
+        };
+        let response = message.process(&session());
+
+        // Assert
+        let decoded = ExportResponse::from_bytes(&response.to_bytes()).unwrap();
+        assert_eq!(decoded.data(), exported(ExportFormat::Csv).as_bytes());
+        assert_eq!(
+            Request::new(b"OX\0\0\0\0\0\0\0"),
+            Err(Error::Parse(ParseError::InvalidExportFormat))
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use crate::{Price, Timestamp};
+
+/// Number of bits of a timestamp, i.e. the depth of the tree.
+const DEPTH: u32 = Timestamp::BITS;
+/// Marks a missing child. The root is never a child, so its slot is free.
+const NONE: u32 = 0;
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    count: u64,
+    sum: i64,
+    children: [u32; 2],
+}
+
+/// Dynamic segment tree over the whole timestamp domain, holding the number of
+/// prices and their sum for every subtree.
+///
+/// Nodes are only allocated along the paths of the timestamps held, so memory
+/// grows with the number of prices rather than with the domain. Subtrees left
+/// empty by a removal are detached and their nodes reused, so evicting prices
+/// gives their memory back to the index. Updates and range queries visit one
+/// node per level, which makes them O(log |domain|) regardless of how many
+/// prices fall in the range.
+#[derive(Clone, Debug)]
+pub(crate) struct PriceIndex {
+    nodes: Vec<Node>,
+    /// Detached nodes, ready to be reused.
+    free: Vec<u32>,
+}
+
+impl PriceIndex {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+            free: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, timestamp: Timestamp, price: Price) {
+        let key = key(timestamp);
+
+        let mut node = 0;
+        for level in (0..DEPTH).rev() {
+            self.apply(node, 1, price as i64);
+
+            let side = ((key >> level) & 1) as usize;
+            let child = match self.nodes[node].children[side] {
+                NONE => {
+                    let child = self.allocate();
+                    self.nodes[node].children[side] = child;
+                    child
+                }
+                child => child,
+            };
+            node = child as usize;
+        }
+        self.apply(node, 1, price as i64);
+    }
+
+    /// Removes a price that was added at `timestamp`.
+    pub fn remove(&mut self, timestamp: Timestamp, price: Price) {
+        let key = key(timestamp);
+
+        let mut node = 0;
+        for level in (0..DEPTH).rev() {
+            self.apply(node, -1, -(price as i64));
+
+            let side = ((key >> level) & 1) as usize;
+            let child = self.nodes[node].children[side];
+            debug_assert_ne!(child, NONE, "removing a price that was never added");
+            if self.nodes[child as usize].count == 1 {
+                // The subtree only held this price, so it is a single path
+                self.nodes[node].children[side] = NONE;
+                self.release_path(child, key, level);
+                return;
+            }
+            node = child as usize;
+        }
+        self.apply(node, -1, -(price as i64));
+    }
+
+    /// Nodes held by the index, in use or free.
+    #[cfg(test)]
+    pub fn allocated(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Number of prices and their sum for timestamps in
+    /// `min_time..=max_time`.
+    pub fn count_and_sum(&self, min_time: Timestamp, max_time: Timestamp) -> (u64, i64) {
+        if min_time > max_time {
+            return (0, 0);
+        }
+
+        let (count_hi, sum_hi) = self.below(key(max_time) + 1);
+        let (count_lo, sum_lo) = self.below(key(min_time));
+
+        (count_hi - count_lo, sum_hi - sum_lo)
+    }
+
+    fn allocate(&mut self) -> u32 {
+        match self.free.pop() {
+            Some(node) => node,
+            None => {
+                self.nodes.push(Node::default());
+                (self.nodes.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Frees `node`, the child taken for bit `level` of `key`, and the nodes
+    /// below it down to the leaf of `key`.
+    fn release_path(&mut self, node: u32, key: u64, level: u32) {
+        let mut node = node;
+        for level in (0..level).rev() {
+            let side = ((key >> level) & 1) as usize;
+            let child = self.nodes[node as usize].children[side];
+            self.release(node);
+            node = child;
+        }
+        self.release(node);
+    }
+
+    fn release(&mut self, node: u32) {
+        self.nodes[node as usize] = Node::default();
+        self.free.push(node);
+    }
+
+    fn apply(&mut self, node: usize, count: i64, sum: i64) {
+        let node = &mut self.nodes[node];
+        node.count = node.count.wrapping_add_signed(count);
+        node.sum += sum;
+    }
+
+    /// Number of prices and their sum for keys strictly below `key`.
+    fn below(&self, key: u64) -> (u64, i64) {
+        let root = &self.nodes[0];
+        if key >> DEPTH != 0 {
+            return (root.count, root.sum);
+        }
+
+        let (mut count, mut sum) = (0, 0);
+        let mut node = root;
+        for level in (0..DEPTH).rev() {
+            let side = ((key >> level) & 1) as usize;
+            if side == 1 {
+                if let Some(left) = self.child(node, 0) {
+                    count += left.count;
+                    sum += left.sum;
+                }
+            }
+
+            match self.child(node, side) {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+
+        (count, sum)
+    }
+
+    fn child(&self, node: &Node, side: usize) -> Option<&Node> {
+        match node.children[side] {
+            NONE => None,
+            child => Some(&self.nodes[child as usize]),
+        }
+    }
+}
+
+/// Maps a timestamp to an unsigned key that preserves ordering.
+fn key(timestamp: Timestamp) -> u64 {
+    (timestamp as i64 - Timestamp::MIN as i64) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::index::PriceIndex;
+
+    #[test]
+    fn sums_ranges() {
+        let mut index = PriceIndex::new();
+        for (ts, price) in [(-5, 1), (0, 2), (3, 4), (10, 8)] {
+            index.add(ts, price);
+        }
+
+        assert_eq!(index.count_and_sum(-5, 10), (4, 15));
+        assert_eq!(index.count_and_sum(0, 3), (2, 6));
+        assert_eq!(index.count_and_sum(1, 2), (0, 0));
+        assert_eq!(index.count_and_sum(3, 3), (1, 4));
+        assert_eq!(index.count_and_sum(10, 0), (0, 0));
+    }
+
+    #[test]
+    fn covers_the_whole_domain() {
+        let mut index = PriceIndex::new();
+        index.add(i32::MIN, -1);
+        index.add(i32::MAX, i32::MAX);
+
+        assert_eq!(
+            index.count_and_sum(i32::MIN, i32::MAX),
+            (2, i32::MAX as i64 - 1)
+        );
+        assert_eq!(index.count_and_sum(i32::MIN, i32::MIN), (1, -1));
+        assert_eq!(
+            index.count_and_sum(i32::MAX, i32::MAX),
+            (1, i32::MAX as i64)
+        );
+    }
+
+    #[test]
+    fn removes_prices() {
+        let mut index = PriceIndex::new();
+        index.add(1, 10);
+        index.add(2, 20);
+
+        index.remove(1, 10);
+
+        assert_eq!(index.count_and_sum(0, 5), (1, 20));
+    }
+
+    #[test]
+    fn reuses_the_nodes_of_removed_prices() {
+        // Arrange
+        let mut index = PriceIndex::new();
+        for ts in 0..100 {
+            index.add(ts, ts);
+        }
+        let allocated = index.allocated();
+
+        // Act
+        for round in 1..50 {
+            for ts in 0..100 {
+                index.remove((round - 1) * 1_000 + ts, ts);
+                index.add(round * 1_000 + ts, ts);
+            }
+        }
+        for ts in 0..100 {
+            index.remove(49_000 + ts, ts);
+        }
+
+        // Assert
+        assert!(index.allocated() <= allocated + 32);
+        assert_eq!(index.count_and_sum(i32::MIN, i32::MAX), (0, 0));
+        assert_eq!(index.free.len(), index.allocated() - 1);
+    }
+
+    #[test]
+    fn keeps_prices_sharing_a_timestamp() {
+        let mut index = PriceIndex::new();
+        index.add(5, 10);
+        index.add(5, 20);
+
+        index.remove(5, 10);
+
+        assert_eq!(index.count_and_sum(5, 5), (1, 20));
+        index.remove(5, 20);
+        assert_eq!(index.count_and_sum(5, 5), (0, 0));
+        assert_eq!(index.free.len(), index.allocated() - 1);
+    }
+
+    #[test]
+    fn matches_a_scan_of_the_prices() {
+        // Arrange
+        let mut seed = 0x2545_f491_u64;
+        let mut next = move || {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (seed >> 32) as i32
+        };
+
+        let mut index = PriceIndex::new();
+        let mut prices = BTreeMap::new();
+        for _ in 0..2_000 {
+            let (ts, price) = (next() % 10_000, next());
+            if let Some(previous) = prices.insert(ts, price) {
+                index.remove(ts, previous);
+            }
+            index.add(ts, price);
+        }
+
+        // Act & Assert
+        for _ in 0..500 {
+            let (min_time, max_time) = (next() % 12_000, next() % 12_000);
+            let scan = prices
+                .range(min_time..=max_time.max(min_time))
+                .fold((0, 0), |(count, sum), (_ts, price)| {
+                    (count + 1, sum + *price as i64)
+                });
+
+            let expected = if min_time > max_time { (0, 0) } else { scan };
+            assert_eq!(index.count_and_sum(min_time, max_time), expected);
+        }
+    }
+}
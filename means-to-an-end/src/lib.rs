@@ -1,6 +1,44 @@
-#![allow(dead_code)]
+use std::{io, str::FromStr};
 
-use std::collections::BTreeMap;
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+mod aggregate;
+pub use aggregate::{Aggregate, AggregateMessage, AggregateResponse};
+
+mod assets;
+pub use assets::{AssetMessage, AssetRegistry, AssetSymbol, SharedPrices, ASSET};
+
+mod batch;
+pub use batch::{
+    BatchInsertMessage, BatchQueryMessage, BatchResponse, BATCH_INSERT, BATCH_QUERY, MAX_BATCH_LEN,
+};
+
+mod candles;
+pub use candles::{Candle, CandlesMessage, CandlesResponse, CANDLES, CANDLES_LEN};
+
+mod config;
+pub use config::Config;
+
+mod error;
+pub use error::{Error, ParseError, ERROR_FRAME, RESULT_FRAME};
+
+mod export;
+pub use export::{export, import, ExportFormat, ExportMessage, ExportResponse, EXPORT};
+
+mod index;
+
+mod limits;
+pub use limits::{Limit, LimitPolicy, Limits, Retention};
+
+mod replay;
+pub use replay::{frames, Answer, Replay};
+
+mod session;
+pub use session::SessionPrices;
+
+mod store;
+pub use store::{ClientLease, LiveClients, PriceLog};
 
 #[derive(Debug, PartialEq)]
 pub struct InsertMessage {
@@ -18,12 +56,27 @@ pub struct QueryMessage {
     max_time: Timestamp,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct IdentifyMessage {
+    /// Identifier chosen by the client. Sessions with the same identifier
+    /// share their price history.
+    client_id: ClientId,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Request {
     Insert(InsertMessage),
     Query(QueryMessage),
+    Identify(IdentifyMessage),
+    Aggregate(AggregateMessage),
+    BatchInsert(BatchInsertMessage),
+    BatchQuery(BatchQueryMessage),
+    Asset(AssetMessage),
+    Export(ExportMessage),
+    Candles(CandlesMessage),
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Response {
     /// Represents the mean of the inserted prices with timestamps T, where
     /// `min_time` <= T <= `max_time`. If there are no samples, then the `mean`
@@ -35,54 +88,396 @@ pub struct Response {
 
 pub type Timestamp = i32;
 pub type Price = i32;
+pub type ClientId = u64;
 
-/// Represents the prices associated with a session.
-pub type SessionPrices = BTreeMap<Timestamp, Price>;
+pub type ProjectResult<T> = Result<T, Error>;
+
+/// Type byte of an insert message.
+pub const INSERT: u8 = b'I';
+/// Type byte of a query message.
+pub const QUERY: u8 = b'Q';
+/// Type byte of an identify message.
+pub const IDENTIFY: u8 = b'H';
+
+/// Size of every single request frame: a type byte followed by 8 bytes of
+/// payload. Batch frames are longer.
+pub const REQUEST_LEN: usize = 9;
+
+/// What to do with an insert message whose timestamp already has a price in
+/// the session. The protocol leaves this undefined, so it is chosen per server.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Fail the insert. The server drops the connection.
+    #[default]
+    Reject,
+    /// Keep the existing price and discard the new one.
+    Ignore,
+    /// Replace the existing price with the new one.
+    Overwrite,
+    /// Keep both prices. Queries see them as two samples.
+    KeepBoth,
+    /// Replace the existing price with the mean of the existing and the new
+    /// price, rounded down.
+    Average,
+}
 
-type ProjectResult<T> = Result<T, &'static str>;
+impl FromStr for DuplicatePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "ignore" => Ok(Self::Ignore),
+            "overwrite" => Ok(Self::Overwrite),
+            "keep-both" => Ok(Self::KeepBoth),
+            "average" => Ok(Self::Average),
+            _ => Err("Unknown duplicate policy"),
+        }
+    }
+}
 
 impl Request {
-    pub fn new(bytes: &[u8]) -> Result<Self, &'static str> {
-        match bytes.first() {
-            Some(t) if *t == b'I' => {
-                let timestamp = Self::to_i32(&bytes[1..5])?;
-                let price = Self::to_i32(&bytes[5..])?;
+    /// Parses a whole frame. Fails, without panicking, on any bytes that are
+    /// not exactly one valid frame.
+    pub fn new(bytes: &[u8]) -> ProjectResult<Self> {
+        let Some((&t, payload)) = bytes.split_first() else {
+            return Err(ParseError::InvalidLength.into());
+        };
+
+        match t {
+            INSERT => {
+                let (timestamp, price) = Self::to_pair(payload)?;
 
                 Ok(Self::Insert(InsertMessage { timestamp, price }))
             }
-            Some(t) if *t == b'Q' => {
-                let min_time = Self::to_i32(&bytes[1..5])?;
-                let max_time = Self::to_i32(&bytes[5..])?;
+            QUERY => {
+                let (min_time, max_time) = Self::to_pair(payload)?;
 
                 Ok(Self::Query(QueryMessage { min_time, max_time }))
             }
-            _ => Err("Unknown message type"),
+            IDENTIFY => {
+                let client_id = u64::from_be_bytes(Self::to_array(payload)?);
+
+                Ok(Self::Identify(IdentifyMessage { client_id }))
+            }
+            ASSET => {
+                let symbol = AssetSymbol::from_bytes(Self::to_array(payload)?)?;
+
+                Ok(Self::Asset(AssetMessage::new(symbol)))
+            }
+            EXPORT => Ok(Self::Export(ExportMessage::from_bytes(Self::to_array(
+                payload,
+            )?)?)),
+            CANDLES => Ok(Self::Candles(CandlesMessage::from_bytes(bytes)?)),
+            BATCH_INSERT => Ok(Self::BatchInsert(BatchInsertMessage::from_bytes(bytes)?)),
+            BATCH_QUERY => Ok(Self::BatchQuery(BatchQueryMessage::from_bytes(bytes)?)),
+            t => match Aggregate::from_type_byte(t) {
+                Some(aggregate) => {
+                    let (min_time, max_time) = Self::to_pair(payload)?;
+
+                    Ok(Self::Aggregate(AggregateMessage::new(
+                        aggregate, min_time, max_time,
+                    )))
+                }
+                None => Err(ParseError::UnknownMessageType(t).into()),
+            },
         }
     }
 
-    fn to_i32(bytes: &[u8]) -> ProjectResult<Timestamp> {
-        match <[u8; 4]>::try_from(bytes) {
-            Ok(bs) => Ok(i32::from_be_bytes(bs)),
-            Err(_) => Err("cannot convert to i32"),
+    /// Encodes the request as a frame that [`Request::new`] parses back.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Request::Insert(m) => Self::frame(INSERT, m.timestamp, m.price),
+            Request::Query(m) => Self::frame(QUERY, m.min_time, m.max_time),
+            Request::Aggregate(m) => {
+                Self::frame(m.aggregate().type_byte(), m.min_time(), m.max_time())
+            }
+            Request::Identify(m) => [&[IDENTIFY][..], &m.client_id.to_be_bytes()].concat(),
+            Request::Asset(m) => [&[ASSET][..], &m.symbol().to_bytes()].concat(),
+            Request::Export(m) => [&[EXPORT][..], &m.to_bytes()].concat(),
+            Request::Candles(m) => m.to_bytes().to_vec(),
+            Request::BatchInsert(m) => m.to_bytes(),
+            Request::BatchQuery(m) => m.to_bytes(),
+        }
+    }
+
+    /// Size of the frame starting with `bytes`, or `None` until enough bytes
+    /// are available to tell.
+    pub fn frame_len(bytes: &[u8]) -> ProjectResult<Option<usize>> {
+        match bytes.first() {
+            Some(&t) if t == BATCH_INSERT || t == BATCH_QUERY => batch::frame_len(bytes),
+            Some(&CANDLES) => Ok(Some(CANDLES_LEN)),
+            Some(_) => Ok(Some(REQUEST_LEN)),
+            None => Ok(None),
         }
     }
+
+    fn frame(type_byte: u8, a: i32, b: i32) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(REQUEST_LEN);
+        bytes.push(type_byte);
+        bytes.extend(a.to_be_bytes());
+        bytes.extend(b.to_be_bytes());
+        bytes
+    }
+
+    /// Splits an 8-byte payload into two big-endian `i32`s.
+    fn to_pair(payload: &[u8]) -> ProjectResult<(i32, i32)> {
+        let bytes: [u8; 8] = Self::to_array(payload)?;
+        let (a, b) = bytes.split_at(4);
+
+        Ok((
+            i32::from_be_bytes(a.try_into().unwrap()),
+            i32::from_be_bytes(b.try_into().unwrap()),
+        ))
+    }
+
+    fn to_array<const N: usize>(payload: &[u8]) -> ProjectResult<[u8; N]> {
+        payload
+            .try_into()
+            .map_err(|_| ParseError::InvalidLength.into())
+    }
+}
+
+impl InsertMessage {
+    pub fn new(timestamp: Timestamp, price: Price) -> Self {
+        Self { timestamp, price }
+    }
+
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    pub fn price(&self) -> Price {
+        self.price
+    }
+}
+
+impl QueryMessage {
+    pub fn new(min_time: Timestamp, max_time: Timestamp) -> Self {
+        Self { min_time, max_time }
+    }
+
+    pub fn min_time(&self) -> Timestamp {
+        self.min_time
+    }
+
+    pub fn max_time(&self) -> Timestamp {
+        self.max_time
+    }
+}
+
+impl IdentifyMessage {
+    pub fn new(client_id: ClientId) -> Self {
+        Self { client_id }
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
 }
 
 impl Response {
-    fn to_bytes(&self) -> [u8; 4] {
+    pub fn new(mean: Price) -> Self {
+        Self { mean }
+    }
+
+    pub fn mean(&self) -> Price {
+        self.mean
+    }
+
+    pub fn to_bytes(&self) -> [u8; 4] {
         self.mean.to_be_bytes()
     }
+
+    pub fn from_bytes(bytes: &[u8]) -> ProjectResult<Self> {
+        match <[u8; 4]>::try_from(bytes) {
+            Ok(bs) => Ok(Self::new(Price::from_be_bytes(bs))),
+            Err(_) => Err(ParseError::InvalidLength.into()),
+        }
+    }
+}
+
+/// Splits the byte stream into [`Request`]s and writes responses back, for
+/// both the server and clients.
+///
+/// Frames are decoded as soon as they are fully buffered, whatever way TCP
+/// split or coalesced them. A frame that is not a valid request is still
+/// consumed and yielded as an `Err`, so the caller decides whether the session
+/// goes on. When not even the length of a frame can be trusted, the rest of
+/// the buffer is dropped with it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MeansCodec {
+    error_frames: bool,
+}
+
+impl MeansCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prefixes every result with [`RESULT_FRAME`], so that clients can tell
+    /// them apart from error frames.
+    pub fn with_error_frames(error_frames: bool) -> Self {
+        Self { error_frames }
+    }
+
+    fn put_result(&self, bytes: &[u8], dst: &mut BytesMut) {
+        dst.reserve(bytes.len() + 1);
+        if self.error_frames {
+            dst.put_u8(RESULT_FRAME);
+        }
+        dst.put_slice(bytes);
+    }
+}
+
+impl Decoder for MeansCodec {
+    type Item = ProjectResult<Request>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        let len = match Request::frame_len(src) {
+            Ok(Some(len)) => len,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                src.clear();
+                return Ok(Some(Err(e)));
+            }
+        };
+
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(len);
+        Ok(Some(Request::new(&frame)))
+    }
+}
+
+/// Writes a request, for clients.
+impl Encoder<Request> for MeansCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Request, dst: &mut BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&item.to_bytes());
+        Ok(())
+    }
+}
+
+impl Encoder<Response> for MeansCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Response, dst: &mut BytesMut) -> io::Result<()> {
+        self.put_result(&item.to_bytes(), dst);
+        Ok(())
+    }
+}
+
+impl Encoder<AggregateResponse> for MeansCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: AggregateResponse, dst: &mut BytesMut) -> io::Result<()> {
+        self.put_result(&item.to_bytes(), dst);
+        Ok(())
+    }
+}
+
+impl Encoder<BatchResponse> for MeansCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: BatchResponse, dst: &mut BytesMut) -> io::Result<()> {
+        self.put_result(&item.to_bytes(), dst);
+        Ok(())
+    }
+}
+
+impl Encoder<ExportResponse> for MeansCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: ExportResponse, dst: &mut BytesMut) -> io::Result<()> {
+        self.put_result(&item.to_bytes(), dst);
+        Ok(())
+    }
+}
+
+impl Encoder<CandlesResponse> for MeansCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: CandlesResponse, dst: &mut BytesMut) -> io::Result<()> {
+        self.put_result(&item.to_bytes(), dst);
+        Ok(())
+    }
+}
+
+/// Writes the error frame. Clients only expect it when error frames are
+/// enabled.
+impl Encoder<Error> for MeansCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Error, dst: &mut BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&item.to_frame());
+        Ok(())
+    }
 }
 
 impl InsertMessage {
+    /// Inserts the price, rejecting duplicate timestamps.
     pub fn process(&self, session_prices: &mut SessionPrices) -> ProjectResult<()> {
-        match session_prices.entry(self.timestamp) {
-            std::collections::btree_map::Entry::Vacant(entry) => {
-                entry.insert(self.price);
-                Ok(())
+        self.process_with_policy(session_prices, DuplicatePolicy::Reject)
+    }
+
+    /// Inserts the price with `policy`, within the limits of the session.
+    pub fn process_with_policy(
+        &self,
+        session_prices: &mut SessionPrices,
+        policy: DuplicatePolicy,
+    ) -> ProjectResult<()> {
+        if self.adds_a_price(session_prices, policy) {
+            session_prices.check_limits(&[self.timestamp])?;
+        }
+        self.apply(session_prices, policy)?;
+        session_prices.enforce_limits();
+
+        Ok(())
+    }
+
+    /// Whether the insert stores one more price, rather than failing or
+    /// changing the prices of an existing timestamp.
+    fn adds_a_price(&self, session_prices: &SessionPrices, policy: DuplicatePolicy) -> bool {
+        policy == DuplicatePolicy::KeepBoth || !session_prices.contains(self.timestamp)
+    }
+
+    /// Inserts the price with `policy`, regardless of the limits.
+    fn apply(
+        &self,
+        session_prices: &mut SessionPrices,
+        policy: DuplicatePolicy,
+    ) -> ProjectResult<()> {
+        if !session_prices.contains(self.timestamp) {
+            session_prices.push(self.timestamp, self.price);
+            return Ok(());
+        }
+
+        match policy {
+            DuplicatePolicy::Reject => return Err(Error::DuplicateTimestamp(self.timestamp)),
+            DuplicatePolicy::Ignore => {}
+            DuplicatePolicy::Overwrite => session_prices.insert(self.timestamp, self.price),
+            DuplicatePolicy::KeepBoth => session_prices.push(self.timestamp, self.price),
+            DuplicatePolicy::Average => {
+                let (count, sum) = session_prices
+                    .prices_at(self.timestamp)
+                    .chain([self.price])
+                    .fold((0, 0), |(count, sum), price| {
+                        (count + 1, sum + price as i64)
+                    });
+
+                // The mean of i32 values always fits in an i32
+                let mean = sum.div_euclid(count) as Price;
+                session_prices.insert(self.timestamp, mean);
             }
-            std::collections::btree_map::Entry::Occupied(_) => Err("timestamp already exists"),
         }
+
+        Ok(())
     }
 }
 
@@ -92,32 +487,45 @@ impl QueryMessage {
             return Ok(0);
         }
 
-        let ts_range = self.min_time..=self.max_time;
-        let prices_in_range = session_prices.range(ts_range.clone()).count();
+        let (prices_in_range, sum) = session_prices.count_and_sum(self.min_time, self.max_time);
 
         if prices_in_range == 0 {
             return Ok(0);
         }
 
-        let sum = session_prices
-            .range(ts_range)
-            .map(|(_ts, price)| *price as i64)
-            .sum::<i64>();
         let length = match i64::try_from(prices_in_range) {
             Ok(v) => v,
-            Err(_) => return Err("cannot compute average"),
+            Err(_) => return Err(Error::Overflow),
         };
 
         match i32::try_from(sum / length) {
             Ok(res) => Ok(res),
-            Err(_) => Err("cannot compute average"),
+            Err(_) => Err(Error::Overflow),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{InsertMessage, QueryMessage, Request, Response, SessionPrices};
+    use bytes::BytesMut;
+    use futures::StreamExt;
+    use proptest::prelude::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+    use crate::{
+        Aggregate, AggregateMessage, AggregateResponse, AssetMessage, AssetSymbol,
+        BatchInsertMessage, BatchQueryMessage, BatchResponse, CandlesMessage, DuplicatePolicy,
+        Error, ExportFormat, ExportMessage, IdentifyMessage, InsertMessage, MeansCodec, ParseError,
+        ProjectResult, QueryMessage, Request, Response, SessionPrices, MAX_BATCH_LEN, REQUEST_LEN,
+    };
+
+    fn insert_frame(timestamp: i32, price: i32) -> Vec<u8> {
+        let mut frame = vec![b'I'];
+        frame.extend(timestamp.to_be_bytes());
+        frame.extend(price.to_be_bytes());
+        frame
+    }
 
     #[test]
     fn parses_an_insert_message_to_a_request() {
@@ -165,6 +573,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parses_an_identify_message_to_a_request() {
+        // Arrange
+        let mut network_data = vec![b'H'];
+        network_data.extend(42_u64.to_be_bytes());
+
+        // Act
+        let req = Request::new(&network_data).unwrap();
+
+        // Assert
+        assert_eq!(req, Request::Identify(IdentifyMessage { client_id: 42 }));
+    }
+
+    #[test]
+    fn rejects_unknown_message_types() {
+        let req = Request::new(&[b'Z', 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(req, Err(Error::Parse(ParseError::UnknownMessageType(b'Z'))));
+    }
+
+    #[test]
+    fn rejects_an_empty_frame() {
+        assert_eq!(
+            Request::new(&[]),
+            Err(Error::Parse(ParseError::InvalidLength))
+        );
+    }
+
+    #[test]
+    fn rejects_short_and_long_frames_of_every_type() {
+        for t in b"IQHAONXMCSDW" {
+            for len in (1..REQUEST_LEN).chain([REQUEST_LEN + 1]) {
+                let mut bytes = vec![0; len];
+                bytes[0] = *t;
+
+                assert_eq!(
+                    Request::new(&bytes),
+                    Err(Error::Parse(ParseError::InvalidLength)),
+                    "type {:?} with {} bytes",
+                    *t as char,
+                    len
+                );
+            }
+        }
+    }
+
     #[test]
     fn converts_a_response_to_bytes() {
         let res = Response { mean: 5107 };
@@ -175,6 +629,190 @@ mod tests {
         assert_eq!([0, 0, 19, 243], bytes)
     }
 
+    #[test]
+    fn decodes_a_request_split_across_reads() {
+        // Arrange
+        let mut codec = MeansCodec::new();
+        let frame = insert_frame(12345, 101);
+        let mut buffer = BytesMut::from(&frame[..4]);
+
+        // Act
+        let partial = codec.decode(&mut buffer).unwrap();
+        buffer.extend_from_slice(&frame[4..]);
+        let complete = codec.decode(&mut buffer).unwrap();
+
+        // Assert
+        assert_eq!(partial, None);
+        assert_eq!(
+            complete,
+            Some(Ok(Request::Insert(InsertMessage {
+                timestamp: 12345,
+                price: 101
+            })))
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn decodes_coalesced_requests_one_at_a_time() {
+        // Arrange
+        let mut codec = MeansCodec::new();
+        let mut buffer = BytesMut::new();
+        buffer.extend(insert_frame(1, 10));
+        buffer.extend(insert_frame(2, 20));
+        buffer.extend(&insert_frame(3, 30)[..5]);
+
+        // Act
+        let first = codec.decode(&mut buffer).unwrap();
+        let second = codec.decode(&mut buffer).unwrap();
+        let third = codec.decode(&mut buffer).unwrap();
+
+        // Assert
+        assert!(matches!(first, Some(Ok(Request::Insert(_)))));
+        assert!(matches!(second, Some(Ok(Request::Insert(_)))));
+        assert_eq!(third, None);
+        assert_eq!(buffer.len(), 5);
+    }
+
+    #[test]
+    fn decodes_an_invalid_frame_as_an_error() {
+        let mut codec = MeansCodec::new();
+        let mut buffer = BytesMut::from(&[b'Z', 0, 0, 0, 0, 0, 0, 0, 0][..]);
+
+        let decoded = codec.decode(&mut buffer).unwrap();
+
+        assert_eq!(
+            decoded,
+            Some(Err(Error::Parse(ParseError::UnknownMessageType(b'Z'))))
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn encodes_responses() {
+        // Arrange
+        let mut plain = MeansCodec::new();
+        let mut tagged = MeansCodec::with_error_frames(true);
+        let mut buffer = BytesMut::new();
+
+        // Act
+        plain.encode(Response::new(5107), &mut buffer).unwrap();
+        tagged.encode(Response::new(5107), &mut buffer).unwrap();
+        tagged
+            .encode(AggregateResponse::new(-1), &mut buffer)
+            .unwrap();
+        tagged.encode(Error::Overflow, &mut buffer).unwrap();
+
+        // Assert
+        assert_eq!(
+            &buffer[..],
+            [
+                &[0, 0, 19, 243][..],
+                &[b'R', 0, 0, 19, 243],
+                &[b'R', 255, 255, 255, 255, 255, 255, 255, 255],
+                &[b'E', 3],
+            ]
+            .concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_requests_from_a_fragmented_stream() {
+        // Arrange
+        let (mut client, server) = tokio::io::duplex(64);
+        let bytes: Vec<u8> = (0..3).flat_map(|i| insert_frame(i, i * 10)).collect();
+        let writer = tokio::spawn(async move {
+            // Chunks that straddle frame boundaries
+            for chunk in bytes.chunks(4) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        // Act
+        let requests: Vec<_> = FramedRead::new(server, MeansCodec::new())
+            .map(|frame| frame.unwrap().unwrap())
+            .collect()
+            .await;
+        writer.await.unwrap();
+
+        // Assert
+        assert_eq!(
+            requests,
+            (0..3)
+                .map(|i| Request::Insert(InsertMessage {
+                    timestamp: i,
+                    price: i * 10
+                }))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn requests_round_trip_through_bytes() {
+        let requests = vec![
+            Request::Insert(InsertMessage::new(-5, i32::MAX)),
+            Request::Query(QueryMessage::new(i32::MIN, 7)),
+            Request::Identify(IdentifyMessage::new(u64::MAX)),
+            Request::Aggregate(AggregateMessage::new(Aggregate::Median, 1, 2)),
+            Request::Asset(AssetMessage::new(AssetSymbol::new("BTC").unwrap())),
+            Request::BatchInsert(
+                BatchInsertMessage::new(vec![InsertMessage::new(1, 2), InsertMessage::new(3, 4)])
+                    .unwrap(),
+            ),
+            Request::BatchQuery(BatchQueryMessage::new(vec![]).unwrap()),
+            Request::Export(ExportMessage::new(ExportFormat::Json)),
+            Request::Candles(CandlesMessage::new(i32::MIN, i32::MAX, u32::MAX).unwrap()),
+        ];
+
+        for request in requests {
+            let bytes = request.to_bytes();
+
+            assert_eq!(Request::frame_len(&bytes), Ok(Some(bytes.len())));
+            assert_eq!(Request::new(&bytes), Ok(request));
+        }
+    }
+
+    #[test]
+    fn responses_round_trip_through_bytes() {
+        let mean = Response::new(-42);
+        let aggregate = AggregateResponse::new(i64::MIN);
+        let batch = BatchResponse::new(vec![1, -1, 0]);
+
+        assert_eq!(Response::from_bytes(&mean.to_bytes()), Ok(mean));
+        assert_eq!(
+            AggregateResponse::from_bytes(&aggregate.to_bytes()),
+            Ok(aggregate)
+        );
+        assert_eq!(BatchResponse::from_bytes(&batch.to_bytes()), Ok(batch));
+    }
+
+    #[test]
+    fn rejects_truncated_responses() {
+        let batch = BatchResponse::new(vec![1, 2]).to_bytes();
+
+        assert_eq!(
+            Response::from_bytes(&[0, 0, 1]),
+            Err(Error::Parse(ParseError::InvalidLength))
+        );
+        assert_eq!(
+            BatchResponse::from_bytes(&batch[..batch.len() - 1]),
+            Err(Error::Parse(ParseError::InvalidLength))
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_batches() {
+        let queries = (0..=MAX_BATCH_LEN as i32)
+            .map(|i| QueryMessage::new(i, i))
+            .collect();
+
+        assert!(matches!(
+            BatchQueryMessage::new(queries),
+            Err(Error::Parse(ParseError::BatchTooLarge(_)))
+        ));
+    }
+
     #[test]
     fn process_a_session() {
         // Arrange
@@ -209,4 +847,184 @@ mod tests {
         // Assert
         assert_eq!(mean, 101);
     }
+
+    fn process_duplicate(policy: DuplicatePolicy) -> (ProjectResult<()>, Vec<(i32, i32)>) {
+        let mut session_prices = SessionPrices::new();
+        InsertMessage {
+            timestamp: 100,
+            price: 10,
+        }
+        .process_with_policy(&mut session_prices, policy)
+        .unwrap();
+
+        let res = InsertMessage {
+            timestamp: 100,
+            price: 15,
+        }
+        .process_with_policy(&mut session_prices, policy);
+
+        (res, session_prices.into_iter().collect())
+    }
+
+    #[test]
+    fn reject_policy_fails_on_duplicates() {
+        let (res, prices) = process_duplicate(DuplicatePolicy::Reject);
+
+        assert_eq!(res, Err(Error::DuplicateTimestamp(100)));
+        assert_eq!(prices, [(100, 10)]);
+    }
+
+    #[test]
+    fn ignore_policy_keeps_the_first_price() {
+        let (res, prices) = process_duplicate(DuplicatePolicy::Ignore);
+
+        assert!(res.is_ok());
+        assert_eq!(prices, [(100, 10)]);
+    }
+
+    #[test]
+    fn overwrite_policy_keeps_the_latest_price() {
+        let (res, prices) = process_duplicate(DuplicatePolicy::Overwrite);
+
+        assert!(res.is_ok());
+        assert_eq!(prices, [(100, 15)]);
+    }
+
+    #[test]
+    fn keep_both_policy_keeps_every_price() {
+        let (res, prices) = process_duplicate(DuplicatePolicy::KeepBoth);
+
+        assert!(res.is_ok());
+        assert_eq!(prices, [(100, 10), (100, 15)]);
+    }
+
+    #[test]
+    fn keep_both_policy_counts_both_prices_in_the_mean() {
+        let mut session_prices = SessionPrices::new();
+        for (timestamp, price) in [(1, 10), (1, 20), (2, 60)] {
+            InsertMessage { timestamp, price }
+                .process_with_policy(&mut session_prices, DuplicatePolicy::KeepBoth)
+                .unwrap();
+        }
+
+        let mean = QueryMessage {
+            min_time: 0,
+            max_time: 5,
+        }
+        .process(&session_prices)
+        .unwrap();
+
+        assert_eq!(mean, 30);
+    }
+
+    #[test]
+    fn average_policy_keeps_the_mean_price() {
+        let (res, prices) = process_duplicate(DuplicatePolicy::Average);
+
+        assert!(res.is_ok());
+        // 12.5 is rounded down
+        assert_eq!(prices, [(100, 12)]);
+    }
+
+    #[test]
+    fn parses_duplicate_policies() {
+        assert_eq!("keep-both".parse(), Ok(DuplicatePolicy::KeepBoth));
+        assert_eq!("Average".parse(), Ok(DuplicatePolicy::Average));
+        assert!("first".parse::<DuplicatePolicy>().is_err());
+    }
+
+    fn arb_pair() -> impl Strategy<Value = (i32, i32)> {
+        (any::<i32>(), any::<i32>())
+    }
+
+    fn arb_request() -> impl Strategy<Value = Request> {
+        let aggregates = b"NXMCSDW".map(|t| Aggregate::from_type_byte(t).unwrap());
+
+        prop_oneof![
+            arb_pair().prop_map(|(ts, price)| Request::Insert(InsertMessage::new(ts, price))),
+            arb_pair().prop_map(|(min, max)| Request::Query(QueryMessage::new(min, max))),
+            any::<u64>().prop_map(|id| Request::Identify(IdentifyMessage::new(id))),
+            (prop::sample::select(aggregates.to_vec()), arb_pair()).prop_map(
+                |(aggregate, (min, max))| {
+                    Request::Aggregate(AggregateMessage::new(aggregate, min, max))
+                }
+            ),
+            "[!-~]{1,8}".prop_map(|symbol| {
+                Request::Asset(AssetMessage::new(AssetSymbol::new(&symbol).unwrap()))
+            }),
+            prop::collection::vec(arb_pair(), 0..20).prop_map(|pairs| {
+                let inserts = pairs
+                    .into_iter()
+                    .map(|(ts, price)| InsertMessage::new(ts, price))
+                    .collect();
+                Request::BatchInsert(BatchInsertMessage::new(inserts).unwrap())
+            }),
+            prop::collection::vec(arb_pair(), 0..20).prop_map(|pairs| {
+                let queries = pairs
+                    .into_iter()
+                    .map(|(min, max)| QueryMessage::new(min, max))
+                    .collect();
+                Request::BatchQuery(BatchQueryMessage::new(queries).unwrap())
+            }),
+            prop_oneof![Just(ExportFormat::Csv), Just(ExportFormat::Json)]
+                .prop_map(|format| Request::Export(ExportMessage::new(format))),
+            (arb_pair(), 1..=u32::MAX).prop_map(|((min, max), width)| {
+                Request::Candles(CandlesMessage::new(min, max, width).unwrap())
+            }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_bytes_never_panic(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let frame_len = Request::frame_len(&bytes);
+
+            // Every frame has a single encoding, so whatever parses must
+            // encode back to the same bytes
+            if let Ok(request) = Request::new(&bytes) {
+                prop_assert_eq!(frame_len, Ok(Some(bytes.len())));
+                prop_assert_eq!(request.to_bytes(), bytes);
+            }
+        }
+
+        #[test]
+        fn requests_survive_a_round_trip(request in arb_request()) {
+            let bytes = request.to_bytes();
+
+            prop_assert_eq!(Request::frame_len(&bytes), Ok(Some(bytes.len())));
+            prop_assert_eq!(Request::new(&bytes), Ok(request));
+        }
+
+        #[test]
+        fn decoding_does_not_depend_on_chunking(
+            requests in prop::collection::vec(arb_request(), 0..10),
+            chunk_size in 1..32_usize,
+        ) {
+            let bytes: Vec<u8> = requests.iter().flat_map(Request::to_bytes).collect();
+            let mut codec = MeansCodec::new();
+            let mut buffer = BytesMut::new();
+            let mut decoded = Vec::new();
+
+            for chunk in bytes.chunks(chunk_size) {
+                buffer.extend_from_slice(chunk);
+                while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                    decoded.push(frame.unwrap());
+                }
+            }
+
+            prop_assert!(buffer.is_empty());
+            prop_assert_eq!(decoded, requests);
+        }
+
+        #[test]
+        fn decoding_arbitrary_streams_never_panics(
+            bytes in prop::collection::vec(any::<u8>(), 0..256),
+        ) {
+            let mut codec = MeansCodec::new();
+            let mut buffer = BytesMut::from(&bytes[..]);
+
+            while let Some(_frame) = codec.decode(&mut buffer).unwrap() {}
+            let _ = codec.decode_eof(&mut buffer);
+        }
+    }
 }
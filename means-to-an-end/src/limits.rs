@@ -0,0 +1,289 @@
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// What to do with an insert that would break a limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LimitPolicy {
+    /// Make room by evicting the oldest prices of the session. A price that
+    /// would itself be the oldest, or is already past the age limit, is
+    /// dropped.
+    #[default]
+    Evict,
+    /// Fail the insert and leave the session untouched.
+    Reject,
+}
+
+/// The limit an insert ran into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    /// The price is older than the retention window of the session.
+    Age,
+    /// The session holds as many prices as allowed.
+    SessionPrices,
+    /// All sessions together hold as many prices as allowed.
+    TotalPrices,
+    /// As many assets as allowed are named.
+    Assets,
+}
+
+/// Bounds on the prices kept in memory. `None` leaves a dimension unbounded.
+///
+/// - `max_age`: only prices at most `max_age` seconds older than the newest
+///   price of the session are kept. Older prices are evicted as newer ones
+///   arrive, whatever the policy. The policy only decides what happens to an
+///   insert that is already too old.
+/// - `max_session_prices`: prices kept per session.
+/// - `max_total_prices`: prices kept across all sessions. With
+///   [`LimitPolicy::Evict`], a session only ever evicts its own prices, so a
+///   session with nothing to give up is rejected anyway.
+///
+/// A batch is checked as a whole: with [`LimitPolicy::Reject`] it is rejected
+/// if any of its prices would be.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    pub max_age: Option<u32>,
+    pub max_session_prices: Option<usize>,
+    pub max_total_prices: Option<usize>,
+    pub policy: LimitPolicy,
+}
+
+/// [`Retention`] shared by the sessions of a server, together with the number
+/// of prices they hold.
+#[derive(Debug, Default)]
+pub struct Limits {
+    retention: Retention,
+    total_prices: AtomicUsize,
+}
+
+impl FromStr for LimitPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "evict" => Ok(Self::Evict),
+            "reject" => Ok(Self::Reject),
+            _ => Err("Unknown limit policy"),
+        }
+    }
+}
+
+impl Limits {
+    pub fn new(retention: Retention) -> Self {
+        Self {
+            retention,
+            total_prices: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn retention(&self) -> &Retention {
+        &self.retention
+    }
+
+    /// Prices held by all the sessions sharing these limits.
+    pub fn total_prices(&self) -> usize {
+        self.total_prices.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn acquire(&self, count: usize) {
+        self.total_prices.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn release(&self, count: usize) {
+        self.total_prices.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        BatchInsertMessage, DuplicatePolicy, Error, InsertMessage, Limit, LimitPolicy, Limits,
+        Retention, SessionPrices,
+    };
+
+    fn session(retention: Retention) -> SessionPrices {
+        SessionPrices::with_limits(Arc::new(Limits::new(retention)))
+    }
+
+    fn insert(session_prices: &mut SessionPrices, timestamp: i32) -> Result<(), Error> {
+        InsertMessage::new(timestamp, timestamp).process(session_prices)
+    }
+
+    fn timestamps(session_prices: &SessionPrices) -> Vec<i32> {
+        session_prices.iter().map(|(ts, _price)| ts).collect()
+    }
+
+    #[test]
+    fn evicts_prices_that_fall_out_of_the_window() {
+        let mut session_prices = session(Retention {
+            max_age: Some(10),
+            ..Retention::default()
+        });
+
+        for ts in [100, 95, 108, 120, 80] {
+            insert(&mut session_prices, ts).unwrap();
+        }
+
+        assert_eq!(timestamps(&session_prices), vec![120]);
+        insert(&mut session_prices, 110).unwrap();
+        assert_eq!(timestamps(&session_prices), vec![110, 120]);
+    }
+
+    #[test]
+    fn rejects_prices_older_than_the_window() {
+        let mut session_prices = session(Retention {
+            max_age: Some(10),
+            policy: LimitPolicy::Reject,
+            ..Retention::default()
+        });
+        insert(&mut session_prices, 100).unwrap();
+
+        let too_old = insert(&mut session_prices, 89);
+        insert(&mut session_prices, 115).unwrap();
+
+        assert_eq!(too_old, Err(Error::LimitExceeded(Limit::Age)));
+        assert_eq!(timestamps(&session_prices), vec![115]);
+    }
+
+    #[test]
+    fn evicts_the_oldest_prices_of_a_full_session() {
+        let mut session_prices = session(Retention {
+            max_session_prices: Some(3),
+            ..Retention::default()
+        });
+
+        for ts in [5, 1, 4, 3, 2, 6] {
+            insert(&mut session_prices, ts).unwrap();
+        }
+
+        assert_eq!(timestamps(&session_prices), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn rejects_inserts_into_a_full_session() {
+        let mut session_prices = session(Retention {
+            max_session_prices: Some(2),
+            policy: LimitPolicy::Reject,
+            ..Retention::default()
+        });
+        insert(&mut session_prices, 1).unwrap();
+        insert(&mut session_prices, 2).unwrap();
+
+        let full = insert(&mut session_prices, 3);
+        // Replacing a price does not need room
+        let overwrite = InsertMessage::new(2, 20)
+            .process_with_policy(&mut session_prices, DuplicatePolicy::Overwrite);
+
+        assert_eq!(full, Err(Error::LimitExceeded(Limit::SessionPrices)));
+        assert_eq!(overwrite, Ok(()));
+        assert_eq!(timestamps(&session_prices), vec![1, 2]);
+    }
+
+    #[test]
+    fn sessions_share_the_total_budget() {
+        // Arrange
+        let limits = Arc::new(Limits::new(Retention {
+            max_total_prices: Some(3),
+            ..Retention::default()
+        }));
+        let mut first = SessionPrices::with_limits(limits.clone());
+        let mut second = SessionPrices::with_limits(limits.clone());
+
+        // Act
+        for ts in [1, 2, 3] {
+            insert(&mut first, ts).unwrap();
+        }
+        let nothing_to_give_up = insert(&mut second, 10);
+        insert(&mut first, 4).unwrap();
+        let total_while_full = limits.total_prices();
+        drop(first);
+
+        // Assert
+        assert_eq!(
+            nothing_to_give_up,
+            Err(Error::LimitExceeded(Limit::TotalPrices))
+        );
+        assert_eq!(total_while_full, 3);
+        assert_eq!(limits.total_prices(), 0);
+        insert(&mut second, 10).unwrap();
+        assert_eq!(limits.total_prices(), 1);
+    }
+
+    #[test]
+    fn rejects_a_batch_as_a_whole() {
+        // Arrange
+        let mut session_prices = session(Retention {
+            max_session_prices: Some(3),
+            policy: LimitPolicy::Reject,
+            ..Retention::default()
+        });
+        insert(&mut session_prices, 1).unwrap();
+        let batch = BatchInsertMessage::new(
+            [2, 3, 4]
+                .into_iter()
+                .map(|ts| InsertMessage::new(ts, ts))
+                .collect(),
+        )
+        .unwrap();
+
+        // Act
+        let result = batch.process(&mut session_prices);
+
+        // Assert
+        assert_eq!(result, Err(Error::LimitExceeded(Limit::SessionPrices)));
+        assert_eq!(timestamps(&session_prices), vec![1]);
+    }
+
+    #[test]
+    fn a_full_session_under_churn_keeps_a_stable_index() {
+        // Arrange
+        let mut session_prices = session(Retention {
+            max_session_prices: Some(100),
+            ..Retention::default()
+        });
+        for ts in 0..1_000 {
+            insert(&mut session_prices, ts * 7).unwrap();
+        }
+        let allocated = session_prices.index_allocated();
+
+        // Act
+        for ts in 1_000..100_000 {
+            insert(&mut session_prices, ts * 7).unwrap();
+        }
+
+        // Assert
+        assert_eq!(session_prices.len(), 100);
+        // A path per price, plus one for a new price waiting for the eviction
+        assert!(session_prices.index_allocated() <= 1 + 32 * 101);
+        assert!(session_prices.index_allocated() <= allocated + 32);
+    }
+
+    #[test]
+    fn evictions_from_the_retention_window_free_the_index() {
+        let mut session_prices = session(Retention {
+            max_age: Some(50),
+            ..Retention::default()
+        });
+        for ts in 0..1_000 {
+            insert(&mut session_prices, ts).unwrap();
+        }
+        let allocated = session_prices.index_allocated();
+
+        for ts in 1_000..50_000 {
+            insert(&mut session_prices, ts).unwrap();
+        }
+
+        assert_eq!(session_prices.len(), 51);
+        assert!(session_prices.index_allocated() <= allocated + 32);
+    }
+
+    #[test]
+    fn parses_limit_policies() {
+        assert_eq!("evict".parse(), Ok(LimitPolicy::Evict));
+        assert_eq!("Reject".parse(), Ok(LimitPolicy::Reject));
+        assert!("drop".parse::<LimitPolicy>().is_err());
+    }
+}
@@ -1,105 +1,380 @@
 use std::{
-    io::{BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
-    thread::{self, ThreadId},
+    fs::{self, File},
+    io::{self, BufWriter},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use env_logger::Env;
+use futures::{SinkExt, StreamExt};
 use log::{debug, error, info};
-use means_to_an_end::{Request, SessionPrices};
+use means_to_an_end::{
+    AggregateResponse, AssetRegistry, BatchResponse, CandlesResponse, ClientLease, Config, Error,
+    ExportFormat, ExportResponse, Limits, LiveClients, MeansCodec, PriceLog, Request, Response,
+    SessionPrices, SharedPrices,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use tokio_util::codec::Framed;
 use utils::addr;
 
-fn handle_connection(mut connection: TcpStream, tid: ThreadId) {
-    info!(
-        "{:?} - Established connection with: {:?}",
-        tid,
-        connection.peer_addr()
-    );
-    let mut reader = BufReader::new(connection.try_clone().unwrap());
+/// Why a request could not be answered.
+enum Failure {
+    /// The client did something wrong. Reported with an error frame when the
+    /// extension is enabled.
+    Client(Error),
+    /// The server could not persist or restore the session.
+    Storage(io::Error),
+}
 
-    let mut session_prices = SessionPrices::new();
-    loop {
-        let mut buffer = [0; 9];
-        if let Err(e) = reader.read_exact(&mut buffer) {
-            error!(
-                "{:?} - Cannot read from the socket. Dropping connection: {:?}",
-                tid, e
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Failure::Client(e)
+    }
+}
+
+/// What to send back for a request.
+enum Reply {
+    Mean(Response),
+    Aggregate(AggregateResponse),
+    Batch(BatchResponse),
+    Export(ExportResponse),
+    Candles(CandlesResponse),
+}
+
+/// Per-connection state.
+struct Session {
+    /// Private to the connection, unless it named an asset.
+    prices: SharedPrices,
+    price_log: Option<PriceLog>,
+    /// Keeps other connections from identifying as the same client.
+    lease: Option<ClientLease>,
+    is_first_message: bool,
+}
+
+/// Processes a request, returning the reply to send back, if any. Price logs
+/// are plain files, so the caller runs this off the reactor when persistence
+/// is enabled.
+fn handle_request(
+    request: Request,
+    session: &mut Session,
+    peer: SocketAddr,
+    config: &Config,
+    assets: &AssetRegistry,
+    limits: &Arc<Limits>,
+    live_clients: &LiveClients,
+) -> Result<Option<Reply>, Failure> {
+    let is_first = std::mem::replace(&mut session.is_first_message, false);
+    match request {
+        Request::Insert(insert_message) => {
+            insert_message.process_with_policy(
+                &mut session.prices.write().unwrap(),
+                config.duplicate_policy,
+            )?;
+            info!("{} - Processed insert message {:?}", peer, insert_message);
+            if let Some(price_log) = session.price_log.as_mut() {
+                price_log
+                    .append(&insert_message)
+                    .and_then(|()| price_log.compact(&session.prices.read().unwrap()))
+                    .map_err(Failure::Storage)?;
+            }
+            Ok(None)
+        }
+        Request::BatchInsert(batch_message) => {
+            batch_message.process_with_policy(
+                &mut session.prices.write().unwrap(),
+                config.duplicate_policy,
+            )?;
+            info!(
+                "{} - Processed a batch of {} inserts",
+                peer,
+                batch_message.inserts().len()
             );
-            break;
+            if let Some(price_log) = session.price_log.as_mut() {
+                price_log
+                    .append_all(batch_message.inserts())
+                    .and_then(|()| price_log.compact(&session.prices.read().unwrap()))
+                    .map_err(Failure::Storage)?;
+            }
+            Ok(None)
+        }
+        Request::Query(query_message) => {
+            let mean = query_message.process(&session.prices.read().unwrap())?;
+            info!("{} - Computed mean {:?}", peer, mean);
+            Ok(Some(Reply::Mean(Response::new(mean))))
+        }
+        Request::Aggregate(aggregate_message) => {
+            let value = aggregate_message.process(&session.prices.read().unwrap())?;
+            info!(
+                "{} - Computed {:?} {:?}",
+                peer,
+                aggregate_message.aggregate(),
+                value
+            );
+            Ok(Some(Reply::Aggregate(AggregateResponse::new(value))))
+        }
+        Request::BatchQuery(batch_message) => {
+            let response = batch_message.process(&session.prices.read().unwrap())?;
+            info!("{} - Computed means {:?}", peer, response.means());
+            Ok(Some(Reply::Batch(response)))
+        }
+        Request::Identify(identify_message) => {
+            let Some(data_dir) = config.data_dir.as_ref() else {
+                return Err(Error::Protocol("persistent sessions are disabled").into());
+            };
+            if !is_first {
+                return Err(Error::Protocol("identify must be the first message").into());
+            }
+            let Some(lease) = live_clients.claim(identify_message.client_id()) else {
+                return Err(Error::Protocol("client already has a live session").into());
+            };
+            let (log, prices) = PriceLog::open(
+                data_dir,
+                identify_message.client_id(),
+                config.duplicate_policy,
+                limits.clone(),
+            )
+            .map_err(Failure::Storage)?;
+            info!(
+                "{} - Restored {} prices for client {}",
+                peer,
+                prices.len(),
+                identify_message.client_id()
+            );
+            session.price_log = Some(log);
+            session.lease = Some(lease);
+            session.prices = Arc::new(RwLock::new(prices));
+            Ok(None)
+        }
+        Request::Asset(asset_message) => {
+            if !config.named_assets {
+                return Err(Error::Protocol("named assets are disabled").into());
+            }
+            if !is_first {
+                return Err(Error::Protocol("asset must be the first message").into());
+            }
+            session.prices = assets.prices(asset_message.symbol())?;
+            info!(
+                "{} - Sharing the prices of asset {}",
+                peer,
+                asset_message.symbol()
+            );
+            Ok(None)
+        }
+        Request::Candles(candles_message) => {
+            let response = candles_message.process(&session.prices.read().unwrap());
+            info!(
+                "{} - Computed {} candles of {}s",
+                peer,
+                response.candles().len(),
+                candles_message.width()
+            );
+            Ok(Some(Reply::Candles(response)))
+        }
+        Request::Export(export_message) => {
+            if !config.admin_commands {
+                return Err(Error::Protocol("admin commands are disabled").into());
+            }
+            let response = export_message.process(&session.prices.read().unwrap());
+            info!(
+                "{} - Exported {} bytes of {:?}",
+                peer,
+                response.data().len(),
+                export_message.format()
+            );
+            Ok(Some(Reply::Export(response)))
+        }
+    }
+}
+
+/// Writes the prices of the session to a new file in `dir`, named after the
+/// peer and the current time.
+fn export_session(
+    prices: &SessionPrices,
+    peer: SocketAddr,
+    dir: &Path,
+    format: ExportFormat,
+) -> io::Result<PathBuf> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let name = format!("{}-{}", peer, now).replace([':', '[', ']'], "_");
+    let path = dir.join(name).with_extension(format.extension());
+
+    fs::create_dir_all(dir)?;
+    let writer = BufWriter::new(File::create(&path)?);
+    means_to_an_end::export(prices, format, writer)?;
+
+    Ok(path)
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: Arc<Config>,
+    assets: AssetRegistry,
+    limits: Arc<Limits>,
+    live_clients: LiveClients,
+) {
+    info!("{} - Established connection", peer);
+    let mut framed = Framed::new(stream, MeansCodec::with_error_frames(config.error_frames));
+
+    let mut session = Session {
+        prices: Arc::new(RwLock::new(SessionPrices::with_limits(limits.clone()))),
+        price_log: None,
+        lease: None,
+        is_first_message: true,
+    };
+    while let Some(frame) = framed.next().await {
+        let request = match frame {
+            Ok(request) => request,
+            Err(e) => {
+                error!(
+                    "{} - Cannot read from the socket. Dropping connection: {:?}",
+                    peer, e
+                );
+                break;
+            }
         };
+        debug!("{} - Request: {:?}", peer, request);
 
-        debug!("{:?} - Buffer: {:?}", tid, buffer);
-        match Request::new(&buffer) {
-            Ok(Request::Insert(insert_message)) => {
-                match insert_message.process(&mut session_prices) {
-                    Ok(_) => {
-                        info!("{:?} - Processed insert message {:?}", tid, insert_message);
-                        continue;
-                    }
-                    Err(e) => {
-                        error!(
-                            "{:?} - Cannot process insert message {:?}. Dropping connection: {:?}",
-                            tid, insert_message, e
-                        );
-                        break;
-                    }
-                }
+        let result = request.map_err(Failure::from).and_then(|request| {
+            if config.data_dir.is_some() {
+                task::block_in_place(|| {
+                    handle_request(
+                        request,
+                        &mut session,
+                        peer,
+                        &config,
+                        &assets,
+                        &limits,
+                        &live_clients,
+                    )
+                })
+            } else {
+                handle_request(
+                    request,
+                    &mut session,
+                    peer,
+                    &config,
+                    &assets,
+                    &limits,
+                    &live_clients,
+                )
             }
-            Ok(Request::Query(query_message)) => match query_message.process(&session_prices) {
-                Ok(mean) => match connection.write_all(mean.to_be_bytes().as_slice()) {
-                    Ok(_) => {
-                        info!(
-                            "{:?} - Sent mean {:?} to {:?}",
-                            tid,
-                            mean,
-                            connection.peer_addr()
-                        );
-                        continue;
-                    }
-                    Err(e) => {
-                        error!(
-                            "{:?} - Cannot write to socket. Dropping connection: {:?}",
-                            tid, e
-                        );
-                        break;
-                    }
-                },
-                Err(e) => {
-                    error!(
-                        "{:?} - Cannot process query message {:?}. Dropping connection: {:?}",
-                        tid, query_message, e
-                    );
-                    break;
-                }
-            },
-            Err(e) => {
+        });
+
+        let (sent, keep_going) = match result {
+            Ok(None) => continue,
+            Ok(Some(Reply::Mean(response))) => (framed.send(response).await, true),
+            Ok(Some(Reply::Aggregate(response))) => (framed.send(response).await, true),
+            Ok(Some(Reply::Batch(response))) => (framed.send(response).await, true),
+            Ok(Some(Reply::Export(response))) => (framed.send(response).await, true),
+            Ok(Some(Reply::Candles(response))) => (framed.send(response).await, true),
+            Err(Failure::Client(e)) if config.error_frames => {
+                error!("{} - Cannot process request. Replying with: {}", peer, e);
+                (framed.send(e).await, e.is_recoverable())
+            }
+            Err(Failure::Client(e)) => {
+                error!(
+                    "{} - Cannot process request. Dropping connection: {}",
+                    peer, e
+                );
+                break;
+            }
+            Err(Failure::Storage(e)) => {
                 error!(
-                    "{:?} - Cannot parse request. Dropping connection: {:?}",
-                    tid, e
+                    "{} - Cannot access the price log. Dropping connection: {:?}",
+                    peer, e
                 );
                 break;
             }
+        };
+
+        if let Err(e) = sent {
+            error!(
+                "{} - Cannot write to socket. Dropping connection: {:?}",
+                peer, e
+            );
+            break;
+        }
+        if !keep_going {
+            break;
+        }
+    }
+
+    if let Some(dir) = config.export_dir.clone() {
+        let prices = session.prices.clone();
+        let format = config.export_format;
+        let exported = task::spawn_blocking(move || {
+            export_session(&prices.read().unwrap(), peer, &dir, format)
+        })
+        .await;
+        match exported {
+            Ok(Ok(path)) => info!("{} - Exported the session to {:?}", peer, path),
+            Ok(Err(e)) => error!("{} - Cannot export the session: {:?}", peer, e),
+            Err(e) => error!("{} - Export task failed: {:?}", peer, e),
         }
     }
 
-    info!(
-        "{:?} - Dropping connection with: {:?}",
-        tid,
-        connection.peer_addr()
-    );
+    info!("{} - Dropping connection", peer);
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let env = Env::new().filter_or("LOG_LEVEL", "debug");
     env_logger::init_from_env(env);
 
-    let listener = TcpListener::bind(addr()).expect("Cannot bind to address");
+    let config = Arc::new(Config::from_env());
+    // Clients can only identify themselves when a data directory is configured
+    if let Some(data_dir) = config.data_dir.as_ref() {
+        info!("Persisting identified sessions in: {:?}", data_dir);
+    }
+    info!("Duplicate timestamps policy: {:?}", config.duplicate_policy);
+    if config.error_frames {
+        info!("Reporting errors to clients with error frames");
+    }
+    if config.named_assets {
+        info!(
+            "Sharing prices between clients of the same asset, up to {:?} assets",
+            config.max_assets
+        );
+    }
+    info!("Retention: {:?}", config.retention);
+    if let Some(export_dir) = config.export_dir.as_ref() {
+        info!(
+            "Exporting sessions as {:?} to: {:?}",
+            config.export_format, export_dir
+        );
+    }
+    let limits = Arc::new(Limits::new(config.retention));
+    let mut assets = AssetRegistry::with_limits(limits.clone());
+    if let Some(max_assets) = config.max_assets {
+        assets = assets.with_max_assets(max_assets);
+    }
+    let live_clients = LiveClients::new();
+
+    let listener = TcpListener::bind(addr())
+        .await
+        .expect("Cannot bind to address");
     info!("Started listening on: {:?}", addr());
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(connection) => {
-                thread::spawn(move || handle_connection(connection, thread::current().id()));
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(handle_connection(
+                    stream,
+                    peer,
+                    config.clone(),
+                    assets.clone(),
+                    limits.clone(),
+                    live_clients.clone(),
+                ));
             }
             Err(e) => {
                 error!("Could not establish connection: {:?}", e)
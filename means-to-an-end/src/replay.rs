@@ -0,0 +1,331 @@
+use std::{fmt::Display, sync::Arc};
+
+use crate::{Candle, Config, Error, Limits, Price, ProjectResult, Request, SessionPrices};
+
+/// What the server answered to a request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Answer {
+    Mean(Price),
+    Aggregate(i64),
+    Means(Vec<Price>),
+    Candles(Vec<Candle>),
+    /// The exported prices, in the requested format.
+    Export(Vec<u8>),
+    /// An error frame, after which the session goes on.
+    Error(Error),
+}
+
+/// Runs recorded requests against a single session, offline, to reproduce the
+/// answers the server gave with the same [`Config`].
+///
+/// Identify and asset messages are checked the way the server checks them,
+/// but only matter to a live server otherwise: the replay always runs on the
+/// prices of the recording alone.
+#[derive(Debug)]
+pub struct Replay {
+    session_prices: SessionPrices,
+    config: Config,
+    is_first_message: bool,
+    /// The error that ended the session, if any.
+    ended: Option<Error>,
+}
+
+impl Replay {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            session_prices: SessionPrices::with_limits(Arc::new(Limits::new(config.retention))),
+            config: config.clone(),
+            is_first_message: true,
+            ended: None,
+        }
+    }
+
+    pub fn session_prices(&self) -> &SessionPrices {
+        &self.session_prices
+    }
+
+    /// Processes `request`, returning the answer the server sent, if any.
+    ///
+    /// Fails with the error that ended the session, as the server drops the
+    /// connection instead of answering: any error without error frames, and
+    /// parse errors with them. Every later request fails with the same error.
+    pub fn replay(&mut self, request: &Request) -> ProjectResult<Option<Answer>> {
+        if let Some(e) = self.ended {
+            return Err(e);
+        }
+
+        match self.process(request) {
+            Ok(answer) => Ok(answer),
+            Err(e) if self.config.error_frames && e.is_recoverable() => Ok(Some(Answer::Error(e))),
+            Err(e) => {
+                self.ended = Some(e);
+                Err(e)
+            }
+        }
+    }
+
+    fn process(&mut self, request: &Request) -> ProjectResult<Option<Answer>> {
+        let is_first = std::mem::replace(&mut self.is_first_message, false);
+        let policy = self.config.duplicate_policy;
+
+        let answer = match request {
+            Request::Insert(m) => {
+                m.process_with_policy(&mut self.session_prices, policy)?;
+                None
+            }
+            Request::BatchInsert(m) => {
+                m.process_with_policy(&mut self.session_prices, policy)?;
+                None
+            }
+            Request::Query(m) => Some(Answer::Mean(m.process(&self.session_prices)?)),
+            Request::Aggregate(m) => Some(Answer::Aggregate(m.process(&self.session_prices)?)),
+            Request::BatchQuery(m) => Some(Answer::Means(
+                m.process(&self.session_prices)?.means().to_vec(),
+            )),
+            Request::Candles(m) => Some(Answer::Candles(
+                m.process(&self.session_prices).candles().to_vec(),
+            )),
+            Request::Identify(_) => {
+                if self.config.data_dir.is_none() {
+                    return Err(Error::Protocol("persistent sessions are disabled"));
+                }
+                if !is_first {
+                    return Err(Error::Protocol("identify must be the first message"));
+                }
+                None
+            }
+            Request::Asset(_) => {
+                if !self.config.named_assets {
+                    return Err(Error::Protocol("named assets are disabled"));
+                }
+                if !is_first {
+                    return Err(Error::Protocol("asset must be the first message"));
+                }
+                None
+            }
+            Request::Export(m) => {
+                if !self.config.admin_commands {
+                    return Err(Error::Protocol("admin commands are disabled"));
+                }
+                Some(Answer::Export(
+                    m.process(&self.session_prices).data().to_vec(),
+                ))
+            }
+        };
+
+        Ok(answer)
+    }
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Self::new(&Config::default())
+    }
+}
+
+impl Display for Answer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Answer::Mean(mean) => write!(f, "{mean}"),
+            Answer::Aggregate(value) => write!(f, "{value}"),
+            Answer::Means(means) => write!(f, "{means:?}"),
+            Answer::Candles(candles) => write!(f, "{candles:?}"),
+            Answer::Export(data) => write!(f, "{:?}", String::from_utf8_lossy(data)),
+            Answer::Error(e) => write!(f, "error frame: {e}"),
+        }
+    }
+}
+
+/// Splits a recorded stream into its frames, each with its offset in the
+/// stream. Stops after the first frame that cannot be parsed, since the
+/// frames after it cannot be trusted.
+pub fn frames(stream: &[u8]) -> impl Iterator<Item = (usize, ProjectResult<Request>)> + '_ {
+    let mut offset = 0;
+    let mut failed = false;
+
+    std::iter::from_fn(move || {
+        let rest = &stream[offset..];
+        if failed || rest.is_empty() {
+            return None;
+        }
+
+        let start = offset;
+        let frame = match Request::frame_len(rest) {
+            Ok(Some(len)) if len <= rest.len() => {
+                offset += len;
+                Request::new(&rest[..len])
+            }
+            // A truncated last frame
+            Ok(_) => Request::new(rest),
+            Err(e) => Err(e),
+        };
+        failed = frame.is_err();
+
+        Some((start, frame))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        frames, Answer, Config, DuplicatePolicy, Error, ExportFormat, ExportMessage,
+        IdentifyMessage, InsertMessage, ParseError, QueryMessage, Replay, Request, Retention,
+    };
+
+    fn stream(requests: &[Request]) -> Vec<u8> {
+        requests.iter().flat_map(Request::to_bytes).collect()
+    }
+
+    fn session_with_a_duplicate() -> Vec<u8> {
+        stream(&[
+            Request::Insert(InsertMessage::new(12345, 101)),
+            Request::Insert(InsertMessage::new(12346, 102)),
+            Request::Insert(InsertMessage::new(12347, 100)),
+            Request::Insert(InsertMessage::new(40960, 5)),
+            Request::Query(QueryMessage::new(12288, 16384)),
+            Request::Insert(InsertMessage::new(12345, 1)),
+            Request::Query(QueryMessage::new(0, 50000)),
+        ])
+    }
+
+    fn replay_all(
+        replay: &mut Replay,
+        recorded: &[u8],
+    ) -> Vec<(usize, Result<Option<Answer>, Error>)> {
+        frames(recorded)
+            .map(|(offset, request)| (offset, request.and_then(|r| replay.replay(&r))))
+            .collect()
+    }
+
+    #[test]
+    fn stops_where_the_server_drops_the_connection() {
+        // Arrange
+        let recorded = session_with_a_duplicate();
+        let mut replay = Replay::new(&Config {
+            duplicate_policy: DuplicatePolicy::Reject,
+            ..Config::default()
+        });
+
+        // Act
+        let answers = replay_all(&mut replay, &recorded);
+
+        // Assert
+        assert_eq!(answers[4], (36, Ok(Some(Answer::Mean(101)))));
+        assert_eq!(answers[5], (45, Err(Error::DuplicateTimestamp(12345))));
+        assert_eq!(answers[6], (54, Err(Error::DuplicateTimestamp(12345))));
+        assert_eq!(replay.session_prices().len(), 4);
+    }
+
+    #[test]
+    fn goes_on_after_error_frames() {
+        // Arrange
+        let recorded = session_with_a_duplicate();
+        let mut replay = Replay::new(&Config {
+            duplicate_policy: DuplicatePolicy::Reject,
+            error_frames: true,
+            ..Config::default()
+        });
+
+        // Act
+        let answers = replay_all(&mut replay, &recorded);
+
+        // Assert
+        assert_eq!(
+            answers[5],
+            (
+                45,
+                Ok(Some(Answer::Error(Error::DuplicateTimestamp(12345))))
+            )
+        );
+        assert_eq!(answers[6], (54, Ok(Some(Answer::Mean(77)))));
+    }
+
+    #[test]
+    fn applies_the_retention_of_the_server() {
+        let recorded = stream(&[
+            Request::Insert(InsertMessage::new(1, 10)),
+            Request::Insert(InsertMessage::new(2, 20)),
+            Request::Insert(InsertMessage::new(3, 30)),
+            Request::Query(QueryMessage::new(0, 10)),
+        ]);
+        let mut replay = Replay::new(&Config {
+            retention: Retention {
+                max_session_prices: Some(2),
+                ..Retention::default()
+            },
+            ..Config::default()
+        });
+
+        let answers = replay_all(&mut replay, &recorded);
+
+        assert_eq!(answers[3], (27, Ok(Some(Answer::Mean(25)))));
+    }
+
+    #[test]
+    fn reproduces_exports() {
+        let recorded = stream(&[
+            Request::Insert(InsertMessage::new(2, 20)),
+            Request::Insert(InsertMessage::new(1, 10)),
+            Request::Export(ExportMessage::new(ExportFormat::Csv)),
+        ]);
+        let mut replay = Replay::new(&Config {
+            admin_commands: true,
+            ..Config::default()
+        });
+
+        let answers = replay_all(&mut replay, &recorded);
+
+        assert_eq!(
+            answers[2],
+            (
+                18,
+                Ok(Some(Answer::Export(
+                    b"timestamp,price\n1,10\n2,20\n".to_vec()
+                )))
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_handshakes_the_server_rejects() {
+        let recorded = stream(&[
+            Request::Query(QueryMessage::new(0, 10)),
+            Request::Identify(IdentifyMessage::new(7)),
+        ]);
+        let mut replay = Replay::new(&Config::default());
+
+        let answers = replay_all(&mut replay, &recorded);
+
+        assert_eq!(
+            answers[1],
+            (9, Err(Error::Protocol("persistent sessions are disabled")))
+        );
+    }
+
+    #[test]
+    fn stops_at_the_first_invalid_frame() {
+        let mut recorded = stream(&[Request::Query(QueryMessage::new(0, 1))]);
+        recorded.extend(b"Z12345678");
+        recorded.extend(stream(&[Request::Query(QueryMessage::new(0, 1))]));
+
+        let frames = frames(&recorded).collect::<Vec<_>>();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            frames[1],
+            (9, Err(Error::Parse(ParseError::UnknownMessageType(b'Z'))))
+        );
+    }
+
+    #[test]
+    fn reports_a_truncated_last_frame() {
+        let recorded = stream(&[Request::Query(QueryMessage::new(0, 1))]);
+
+        let frames = frames(&recorded[..5]).collect::<Vec<_>>();
+
+        assert_eq!(
+            frames,
+            vec![(0, Err(Error::Parse(ParseError::InvalidLength)))]
+        );
+    }
+}
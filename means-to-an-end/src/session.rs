@@ -0,0 +1,306 @@
+use std::{
+    collections::{btree_map, BTreeMap},
+    iter::Map,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use crate::{
+    index::PriceIndex, Error, Limit, LimitPolicy, Limits, Price, ProjectResult, Timestamp,
+};
+
+/// Position of a price among the prices sharing its timestamp.
+type Sequence = u32;
+type Key = (Timestamp, Sequence);
+
+/// Represents the prices associated with a session.
+///
+/// Prices are kept ordered by timestamp, and an index over the same prices
+/// answers counts and sums over a period without walking it. A timestamp
+/// usually has a single price, but can hold several depending on the
+/// [`DuplicatePolicy`](crate::DuplicatePolicy).
+///
+/// Sessions created [`with_limits`](SessionPrices::with_limits) count their
+/// prices against the shared [`Limits`]. The limits are only enforced by
+/// inserts (see [`InsertMessage`](crate::InsertMessage)), through
+/// [`check_limits`](SessionPrices::check_limits) and
+/// [`enforce_limits`](SessionPrices::enforce_limits).
+#[derive(Debug)]
+pub struct SessionPrices {
+    prices: BTreeMap<Key, Price>,
+    index: PriceIndex,
+    limits: Option<Arc<Limits>>,
+}
+
+impl SessionPrices {
+    pub fn new() -> Self {
+        Self {
+            prices: BTreeMap::new(),
+            index: PriceIndex::new(),
+            limits: None,
+        }
+    }
+
+    pub fn with_limits(limits: Arc<Limits>) -> Self {
+        Self {
+            prices: BTreeMap::new(),
+            index: PriceIndex::new(),
+            limits: Some(limits),
+        }
+    }
+
+    /// Number of prices, counting every price of a timestamp.
+    pub fn len(&self) -> usize {
+        self.prices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prices.is_empty()
+    }
+
+    pub fn contains(&self, timestamp: Timestamp) -> bool {
+        self.range(timestamp..=timestamp).next().is_some()
+    }
+
+    /// Prices of `timestamp`, in insertion order.
+    pub fn prices_at(&self, timestamp: Timestamp) -> impl Iterator<Item = Price> + '_ {
+        self.range(timestamp..=timestamp).map(|(_ts, price)| price)
+    }
+
+    /// Makes `price` the only price of `timestamp`.
+    pub fn insert(&mut self, timestamp: Timestamp, price: Price) {
+        self.remove(timestamp);
+        self.push(timestamp, price);
+    }
+
+    /// Adds `price` to `timestamp`, after any price it already has.
+    pub fn push(&mut self, timestamp: Timestamp, price: Price) {
+        let sequence = match self
+            .prices
+            .range(Self::keys(timestamp..=timestamp))
+            .next_back()
+        {
+            Some(((_ts, sequence), _price)) => sequence + 1,
+            None => 0,
+        };
+
+        self.prices.insert((timestamp, sequence), price);
+        self.index.add(timestamp, price);
+        if let Some(limits) = &self.limits {
+            limits.acquire(1);
+        }
+    }
+
+    /// Removes every price of `timestamp`, returning how many there were.
+    pub fn remove(&mut self, timestamp: Timestamp) -> usize {
+        let keys = self
+            .prices
+            .range(Self::keys(timestamp..=timestamp))
+            .map(|(key, _price)| *key)
+            .collect::<Vec<_>>();
+
+        for key in &keys {
+            if let Some(price) = self.prices.remove(key) {
+                self.index.remove(timestamp, price);
+            }
+        }
+        if let Some(limits) = &self.limits {
+            limits.release(keys.len());
+        }
+
+        keys.len()
+    }
+
+    /// Removes the price with the lowest timestamp.
+    pub fn pop_oldest(&mut self) -> Option<(Timestamp, Price)> {
+        let ((timestamp, _sequence), price) = self.prices.pop_first()?;
+        self.index.remove(timestamp, price);
+        if let Some(limits) = &self.limits {
+            limits.release(1);
+        }
+
+        Some((timestamp, price))
+    }
+
+    pub fn newest(&self) -> Option<Timestamp> {
+        self.prices
+            .last_key_value()
+            .map(|((ts, _sequence), _price)| *ts)
+    }
+
+    /// Fails if adding prices at `additions` would break a limit under
+    /// [`LimitPolicy::Reject`], or if the session has nothing to evict to
+    /// stay within the total under [`LimitPolicy::Evict`].
+    pub fn check_limits(&self, additions: &[Timestamp]) -> ProjectResult<()> {
+        let Some(limits) = &self.limits else {
+            return Ok(());
+        };
+        let retention = limits.retention();
+        let added = additions.len();
+        if added == 0 {
+            return Ok(());
+        }
+
+        if retention.policy == LimitPolicy::Evict {
+            return match retention.max_total_prices {
+                Some(max) if self.is_empty() && limits.total_prices() + added > max => {
+                    Err(Error::LimitExceeded(Limit::TotalPrices))
+                }
+                _ => Ok(()),
+            };
+        }
+
+        let cutoff = self.cutoff(additions.iter().max().copied());
+        if cutoff.is_some_and(|cutoff| additions.iter().any(|ts| *ts < cutoff)) {
+            return Err(Error::LimitExceeded(Limit::Age));
+        }
+
+        // Prices past the window will be evicted and free their room
+        let kept = match cutoff {
+            Some(cutoff) => self.count_and_sum(cutoff, Timestamp::MAX).0 as usize,
+            None => self.len(),
+        };
+        if matches!(retention.max_session_prices, Some(max) if kept + added > max) {
+            return Err(Error::LimitExceeded(Limit::SessionPrices));
+        }
+        let total = limits.total_prices() - (self.len() - kept);
+        if matches!(retention.max_total_prices, Some(max) if total + added > max) {
+            return Err(Error::LimitExceeded(Limit::TotalPrices));
+        }
+
+        Ok(())
+    }
+
+    /// Evicts the oldest prices until the session is within its limits.
+    pub fn enforce_limits(&mut self) {
+        let Some(limits) = self.limits.clone() else {
+            return;
+        };
+        let retention = limits.retention();
+
+        if let Some(cutoff) = self.cutoff(None) {
+            while self
+                .prices
+                .first_key_value()
+                .is_some_and(|((ts, _), _)| *ts < cutoff)
+            {
+                self.pop_oldest();
+            }
+        }
+        if let Some(max) = retention.max_session_prices {
+            while self.len() > max {
+                self.pop_oldest();
+            }
+        }
+        // Concurrent inserts into other sessions can make this session give
+        // up a little more than its share, never less
+        if let Some(max) = retention.max_total_prices {
+            while limits.total_prices() > max && self.pop_oldest().is_some() {}
+        }
+    }
+
+    /// Oldest timestamp inside the retention window, given the newest price
+    /// of the session and `incoming`.
+    fn cutoff(&self, incoming: Option<Timestamp>) -> Option<Timestamp> {
+        let max_age = self.limits.as_ref()?.retention().max_age?;
+        let newest = self.newest().max(incoming)?;
+
+        Some((newest as i64 - max_age as i64).max(Timestamp::MIN as i64) as Timestamp)
+    }
+
+    pub fn range<R: RangeBounds<Timestamp>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = (Timestamp, Price)> + '_ {
+        self.prices
+            .range(Self::keys(range))
+            .map(|((ts, _sequence), price)| (*ts, *price))
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (Timestamp, Price)> + '_ {
+        self.range(..)
+    }
+
+    /// Number of prices and their sum for timestamps in
+    /// `min_time..=max_time`, in O(log |timestamps|).
+    pub fn count_and_sum(&self, min_time: Timestamp, max_time: Timestamp) -> (u64, i64) {
+        self.index.count_and_sum(min_time, max_time)
+    }
+
+    /// Nodes held by the index of the session.
+    #[cfg(test)]
+    pub(crate) fn index_allocated(&self) -> usize {
+        self.index.allocated()
+    }
+
+    /// Maps a range of timestamps to the range of keys covering all their
+    /// prices.
+    fn keys<R: RangeBounds<Timestamp>>(range: R) -> (Bound<Key>, Bound<Key>) {
+        let start = match range.start_bound() {
+            Bound::Included(ts) => Bound::Included((*ts, Sequence::MIN)),
+            Bound::Excluded(ts) => Bound::Excluded((*ts, Sequence::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(ts) => Bound::Included((*ts, Sequence::MAX)),
+            Bound::Excluded(ts) => Bound::Excluded((*ts, Sequence::MIN)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        (start, end)
+    }
+}
+
+impl Clone for SessionPrices {
+    fn clone(&self) -> Self {
+        if let Some(limits) = &self.limits {
+            limits.acquire(self.len());
+        }
+
+        Self {
+            prices: self.prices.clone(),
+            index: self.index.clone(),
+            limits: self.limits.clone(),
+        }
+    }
+}
+
+impl Drop for SessionPrices {
+    fn drop(&mut self) {
+        if let Some(limits) = &self.limits {
+            limits.release(self.len());
+        }
+    }
+}
+
+impl Default for SessionPrices {
+    fn default() -> Self {
+        SessionPrices::new()
+    }
+}
+
+impl FromIterator<(Timestamp, Price)> for SessionPrices {
+    fn from_iter<T: IntoIterator<Item = (Timestamp, Price)>>(iter: T) -> Self {
+        let mut session_prices = SessionPrices::new();
+        for (timestamp, price) in iter {
+            session_prices.push(timestamp, price);
+        }
+        session_prices
+    }
+}
+
+impl IntoIterator for SessionPrices {
+    type Item = (Timestamp, Price);
+    type IntoIter = Map<btree_map::IntoIter<Key, Price>, fn((Key, Price)) -> (Timestamp, Price)>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        let prices = std::mem::take(&mut self.prices);
+        if let Some(limits) = &self.limits {
+            limits.release(prices.len());
+        }
+
+        prices
+            .into_iter()
+            .map(|((ts, _sequence), price)| (ts, price))
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::{ClientId, DuplicatePolicy, InsertMessage, Limits, SessionPrices};
+
+/// Size of a single record: a big-endian timestamp followed by a big-endian
+/// price, the same layout as the payload of an insert message.
+const RECORD_SIZE: usize = 8;
+
+/// Longest time appended records wait to be synced to disk, as long as more
+/// records follow. The rest is synced when the log is dropped.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Logs of at most this many records are never compacted.
+const MIN_COMPACTED_RECORDS: u64 = 1024;
+
+/// Append-only log of the inserts accepted for a client.
+///
+/// Every client gets its own file in the data directory. Replaying the log
+/// through [`InsertMessage::process`] rebuilds the client's prices, so the
+/// history survives both reconnects and server restarts.
+///
+/// Records of prices that were since evicted or replaced are dropped by
+/// [`compact`](PriceLog::compact), which rewrites the log with the prices
+/// the session kept.
+pub struct PriceLog {
+    path: PathBuf,
+    file: File,
+    /// Records in the file.
+    records: u64,
+    last_sync: Instant,
+    /// Whether records were appended since the last sync.
+    unsynced: bool,
+}
+
+/// Client ids with a live session.
+///
+/// Every session keeps its own copy of the prices and appends to the log of
+/// its client, so two sessions of the same client would drift apart and log
+/// conflicting inserts. A client id can only be claimed by one session at a
+/// time.
+#[derive(Clone, Debug, Default)]
+pub struct LiveClients {
+    ids: Arc<Mutex<HashSet<ClientId>>>,
+}
+
+/// Claim on a client id, released when dropped.
+#[derive(Debug)]
+pub struct ClientLease {
+    client_id: ClientId,
+    ids: Arc<Mutex<HashSet<ClientId>>>,
+}
+
+impl LiveClients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` if another session holds `client_id`.
+    pub fn claim(&self, client_id: ClientId) -> Option<ClientLease> {
+        let mut ids = self.ids.lock().unwrap();
+        if !ids.insert(client_id) {
+            return None;
+        }
+
+        Some(ClientLease {
+            client_id,
+            ids: self.ids.clone(),
+        })
+    }
+
+    pub fn is_live(&self, client_id: ClientId) -> bool {
+        self.ids.lock().unwrap().contains(&client_id)
+    }
+}
+
+impl Drop for ClientLease {
+    fn drop(&mut self) {
+        self.ids.lock().unwrap().remove(&self.client_id);
+    }
+}
+
+impl PriceLog {
+    /// Opens (or creates) the log of `client_id` and returns it together with
+    /// the prices recorded so far, replayed with `policy` into a session
+    /// counted against `limits`. Prices are evicted as the records stream in,
+    /// so the session never holds more than the limits allow.
+    ///
+    /// A record left incomplete by a crash is ignored. Records the policy
+    /// rejects, which only happens if it changed since they were logged, are
+    /// skipped with a warning. The log is rewritten with only the prices
+    /// kept, if any record was not.
+    pub fn open(
+        dir: &Path,
+        client_id: ClientId,
+        policy: DuplicatePolicy,
+        limits: Arc<Limits>,
+    ) -> io::Result<(Self, SessionPrices)> {
+        fs::create_dir_all(dir)?;
+        let path = Self::path(dir, client_id);
+
+        let mut session_prices = SessionPrices::with_limits(limits);
+        let mut records = 0;
+        let mut skipped = 0;
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut record = [0; RECORD_SIZE];
+            loop {
+                match reader.read_exact(&mut record) {
+                    Ok(()) => {
+                        let insert_message = InsertMessage {
+                            timestamp: i32::from_be_bytes(record[..4].try_into().unwrap()),
+                            price: i32::from_be_bytes(record[4..].try_into().unwrap()),
+                        };
+                        if let Err(e) =
+                            insert_message.process_with_policy(&mut session_prices, policy)
+                        {
+                            warn!(
+                                "Client {} - Skipping logged insert {:?}: {}",
+                                client_id, insert_message, e
+                            );
+                            skipped += 1;
+                        }
+                        records += 1;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        if skipped > 0 {
+            warn!(
+                "Client {} - Skipped {} of {} logged inserts with the {:?} policy",
+                client_id, skipped, records, policy
+            );
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Drop the incomplete record, if any, so that new records stay aligned
+        file.set_len(records * RECORD_SIZE as u64)?;
+
+        let mut log = Self {
+            path,
+            file,
+            records,
+            last_sync: Instant::now(),
+            unsynced: false,
+        };
+        if log.records > session_prices.len() as u64 {
+            log.rewrite(&session_prices)?;
+        }
+
+        Ok((log, session_prices))
+    }
+
+    /// Records an insert that was accepted by the session. Replaying it with
+    /// the same policy yields the same prices.
+    pub fn append(&mut self, insert_message: &InsertMessage) -> io::Result<()> {
+        self.append_all(std::slice::from_ref(insert_message))
+    }
+
+    /// Appends several inserts with a single write. The file is synced at
+    /// most once every [`SYNC_INTERVAL`].
+    pub fn append_all(&mut self, insert_messages: &[InsertMessage]) -> io::Result<()> {
+        let mut records = Vec::with_capacity(insert_messages.len() * RECORD_SIZE);
+        for insert_message in insert_messages {
+            records.extend(insert_message.timestamp.to_be_bytes());
+            records.extend(insert_message.price.to_be_bytes());
+        }
+
+        self.file.write_all(&records)?;
+        self.records += insert_messages.len() as u64;
+        self.unsynced = true;
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the log with `session_prices`, the prices the session kept,
+    /// once most of its records are of prices that are gone.
+    pub fn compact(&mut self, session_prices: &SessionPrices) -> io::Result<()> {
+        let kept = session_prices.len() as u64;
+        if self.records > 2 * kept.max(MIN_COMPACTED_RECORDS) {
+            self.rewrite(session_prices)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the file with one record per price, oldest first, which
+    /// replays to the same prices with any policy.
+    fn rewrite(&mut self, session_prices: &SessionPrices) -> io::Result<()> {
+        let mut records = Vec::with_capacity(session_prices.len() * RECORD_SIZE);
+        for (timestamp, price) in session_prices.iter() {
+            records.extend(timestamp.to_be_bytes());
+            records.extend(price.to_be_bytes());
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&records)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = session_prices.len() as u64;
+        self.last_sync = Instant::now();
+        self.unsynced = false;
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        self.unsynced = false;
+
+        Ok(())
+    }
+
+    fn path(dir: &Path, client_id: ClientId) -> PathBuf {
+        dir.join(format!("{client_id}.log"))
+    }
+}
+
+impl Drop for PriceLog {
+    fn drop(&mut self) {
+        if self.unsynced {
+            if let Err(e) = self.sync() {
+                warn!("Cannot sync the price log {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+        sync::Arc,
+    };
+
+    use crate::{
+        DuplicatePolicy, InsertMessage, Limits, LiveClients, PriceLog, QueryMessage, Retention,
+        SessionPrices,
+    };
+
+    #[test]
+    fn history_survives_reopening_the_log() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let inserts = [(100, 10), (200, 20), (300, 30)];
+
+        {
+            let (mut log, mut session_prices) =
+                PriceLog::open(dir.path(), 7, DuplicatePolicy::Reject, Arc::default()).unwrap();
+            for (timestamp, price) in inserts {
+                let insert_message = InsertMessage { timestamp, price };
+                insert_message.process(&mut session_prices).unwrap();
+                log.append(&insert_message).unwrap();
+            }
+        }
+
+        // Act
+        let (_, session_prices) =
+            PriceLog::open(dir.path(), 7, DuplicatePolicy::Reject, Arc::default()).unwrap();
+        let mean = QueryMessage {
+            min_time: 0,
+            max_time: 1000,
+        }
+        .process(&session_prices)
+        .unwrap();
+
+        // Assert
+        assert_eq!(session_prices.len(), 3);
+        assert_eq!(mean, 20);
+    }
+
+    #[test]
+    fn clients_do_not_share_history() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut log, _) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, Arc::default()).unwrap();
+        log.append(&InsertMessage {
+            timestamp: 1,
+            price: 1,
+        })
+        .unwrap();
+
+        let (_, session_prices) =
+            PriceLog::open(dir.path(), 2, DuplicatePolicy::Reject, Arc::default()).unwrap();
+
+        assert!(session_prices.is_empty());
+    }
+
+    #[test]
+    fn drops_a_truncated_record() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let (mut log, _) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, Arc::default()).unwrap();
+        log.append(&InsertMessage {
+            timestamp: 1,
+            price: 5,
+        })
+        .unwrap();
+        drop(log);
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("1.log"))
+            .unwrap();
+        file.write_all(&[0, 0, 0]).unwrap();
+
+        // Act
+        let (mut log, _) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, Arc::default()).unwrap();
+        log.append(&InsertMessage {
+            timestamp: 2,
+            price: 6,
+        })
+        .unwrap();
+        let (_, session_prices) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, Arc::default()).unwrap();
+
+        // Assert
+        assert_eq!(
+            session_prices.into_iter().collect::<Vec<_>>(),
+            [(1, 5), (2, 6)]
+        );
+    }
+
+    #[test]
+    fn replays_with_the_duplicate_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = DuplicatePolicy::KeepBoth;
+
+        let (mut log, mut session_prices) =
+            PriceLog::open(dir.path(), 1, policy, Arc::default()).unwrap();
+        for price in [10, 20] {
+            let insert_message = InsertMessage {
+                timestamp: 1,
+                price,
+            };
+            insert_message
+                .process_with_policy(&mut session_prices, policy)
+                .unwrap();
+            log.append(&insert_message).unwrap();
+        }
+
+        let (_, restored) = PriceLog::open(dir.path(), 1, policy, Arc::default()).unwrap();
+
+        assert_eq!(
+            restored.into_iter().collect::<Vec<_>>(),
+            session_prices.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn a_client_id_has_one_live_session_at_a_time() {
+        // Arrange
+        let live_clients = LiveClients::new();
+        let first = live_clients.claim(7).unwrap();
+
+        // Act
+        let second = live_clients.claim(7);
+        let other_client = live_clients.claim(8);
+        drop(first);
+        let after_close = live_clients.claim(7);
+
+        // Assert
+        assert!(second.is_none());
+        assert!(other_client.is_some());
+        assert!(after_close.is_some());
+        assert!(live_clients.is_live(7));
+    }
+
+    #[test]
+    fn skips_records_the_policy_rejects() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut log, _) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::KeepBoth, Arc::default()).unwrap();
+        for price in [10, 20] {
+            log.append(&InsertMessage {
+                timestamp: 1,
+                price,
+            })
+            .unwrap();
+        }
+
+        let (_, session_prices) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, Arc::default()).unwrap();
+
+        assert_eq!(session_prices.into_iter().collect::<Vec<_>>(), [(1, 10)]);
+    }
+
+    #[test]
+    fn rewrites_the_log_with_the_kept_prices_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut log, _) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Overwrite, Arc::default()).unwrap();
+        for price in [10, 20, 30] {
+            log.append(&InsertMessage {
+                timestamp: 1,
+                price,
+            })
+            .unwrap();
+        }
+        drop(log);
+
+        let (_, session_prices) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Overwrite, Arc::default()).unwrap();
+
+        assert_eq!(session_prices.into_iter().collect::<Vec<_>>(), [(1, 30)]);
+        assert_eq!(fs::metadata(dir.path().join("1.log")).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn compaction_keeps_the_log_bounded_by_the_kept_prices() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let (mut log, _) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, Arc::default()).unwrap();
+        let limits = Arc::new(Limits::new(Retention {
+            max_session_prices: Some(10),
+            ..Retention::default()
+        }));
+        let mut session_prices = SessionPrices::with_limits(limits.clone());
+
+        // Act
+        for timestamp in 0..10_000 {
+            let insert_message = InsertMessage {
+                timestamp,
+                price: timestamp,
+            };
+            insert_message.process(&mut session_prices).unwrap();
+            log.append(&insert_message).unwrap();
+            log.compact(&session_prices).unwrap();
+        }
+        drop(log);
+
+        // Assert
+        let size = fs::metadata(dir.path().join("1.log")).unwrap().len();
+        assert!(size <= 2 * 1024 * 8, "{size} bytes");
+        let (_, restored) = PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, limits).unwrap();
+        assert_eq!(
+            restored.into_iter().collect::<Vec<_>>(),
+            session_prices.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn applies_the_limits_while_replaying() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let (mut log, _) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, Arc::default()).unwrap();
+        for timestamp in 0..100 {
+            log.append(&InsertMessage {
+                timestamp,
+                price: timestamp,
+            })
+            .unwrap();
+        }
+        drop(log);
+        let limits = Arc::new(Limits::new(Retention {
+            max_age: Some(10),
+            max_session_prices: Some(5),
+            ..Retention::default()
+        }));
+
+        // Act
+        let (_, session_prices) =
+            PriceLog::open(dir.path(), 1, DuplicatePolicy::Reject, limits.clone()).unwrap();
+
+        // Assert
+        assert_eq!(
+            session_prices.iter().collect::<Vec<_>>(),
+            (95..100).map(|ts| (ts, ts)).collect::<Vec<_>>()
+        );
+        assert_eq!(limits.total_prices(), 5);
+        assert_eq!(fs::metadata(dir.path().join("1.log")).unwrap().len(), 5 * 8);
+    }
+}
@@ -0,0 +1,18 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use prime_time::{handle_frame, Encoding, PrimeChecker};
+
+static CHECKER: OnceLock<PrimeChecker> = OnceLock::new();
+
+// Every encoding must turn any payload into exactly one response without
+// panicking
+fuzz_target!(|data: &[u8]| {
+    let checker = CHECKER.get_or_init(|| PrimeChecker::new(10_000, 1_024));
+
+    for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+        handle_frame(data, encoding, checker);
+    }
+});
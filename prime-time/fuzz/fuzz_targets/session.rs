@@ -0,0 +1,27 @@
+#![no_main]
+
+use std::{io::Cursor, sync::OnceLock, thread};
+
+use libfuzzer_sys::fuzz_target;
+use prime_time::{threaded::serve, Config, PrimeChecker};
+
+static CHECKER: OnceLock<PrimeChecker> = OnceLock::new();
+
+// Feeds arbitrary bytes through a whole connection, including encoding
+// negotiation and framing
+fuzz_target!(|data: &[u8]| {
+    let checker = CHECKER.get_or_init(|| PrimeChecker::new(10_000, 1_024));
+    let config = Config {
+        max_request_size: 4_096,
+        ..Config::default()
+    };
+    let mut output = Vec::new();
+
+    serve(
+        Cursor::new(data),
+        &mut output,
+        thread::current().id(),
+        &config,
+        checker,
+    );
+});
@@ -0,0 +1,149 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use log::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    task,
+};
+
+use crate::{
+    handle_frame, negotiate_encoding_async, read_frame_async, Config, Encoding, PrimeChecker,
+    ReadLineError, Response,
+};
+
+/// Serves every connection as a tokio task. Idle clients only cost a task, and
+/// primality checks run on the blocking thread pool so they never stall the
+/// reactor.
+pub async fn run(config: Config, checker: Arc<PrimeChecker>) -> io::Result<()> {
+    let listener = TcpListener::bind(&config.bind_addr).await?;
+    info!("Server listening on: {} (async)", config.bind_addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let config = config.clone();
+                let checker = checker.clone();
+                tokio::spawn(handle_connection(stream, peer, config, checker));
+            }
+            Err(e) => {
+                error!("Could not establish connection: {:?}", e)
+            }
+        }
+    }
+}
+
+async fn handle_malformed_request(ws: &mut OwnedWriteHalf, peer: SocketAddr, encoding: Encoding) {
+    let malformed_response = encoding.encode(&Response::Malformed);
+
+    match ws.write_all(&malformed_response).await {
+        Ok(_) => {
+            debug!("{} - Malformed response: {:?}", peer, malformed_response);
+        }
+        Err(e) => {
+            error!("{} - Cannot write to socket: {:?}", peer, e);
+        }
+    }
+
+    info!("{} - Ending connection", peer);
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: Config,
+    checker: Arc<PrimeChecker>,
+) {
+    info!("{} - Established connection", peer);
+
+    let (rs, mut ws) = stream.into_split();
+    let mut reader = BufReader::new(rs);
+
+    let encoding = match config.encoding {
+        Some(encoding) => encoding,
+        None => match negotiate_encoding_async(&mut reader).await {
+            Ok(Some(encoding)) => encoding,
+            Ok(None) => {
+                warn!("{} - Client disconnected", peer);
+                return;
+            }
+            Err(e) => {
+                error!(
+                    "{} - Cannot read from socket. Dropping connection: {:?}",
+                    peer, e
+                );
+                return;
+            }
+        },
+    };
+    debug!("{} - Encoding: {:?}", peer, encoding);
+
+    loop {
+        let request = match read_frame_async(&mut reader, encoding, config.max_request_size).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                warn!("{} - Client disconnected", peer);
+                break;
+            }
+            Err(ReadLineError::TooLong) => {
+                error!(
+                    "{} - Request exceeds {} bytes",
+                    peer, config.max_request_size
+                );
+                return handle_malformed_request(&mut ws, peer, encoding).await;
+            }
+            Err(ReadLineError::InvalidUtf8) => {
+                error!("{} - Request is not valid UTF-8", peer);
+                return handle_malformed_request(&mut ws, peer, encoding).await;
+            }
+            Err(ReadLineError::Io(e)) => {
+                error!(
+                    "{} - Cannot read from socket. Dropping connection: {:?}",
+                    peer, e
+                );
+                break;
+            }
+        };
+
+        debug!(
+            "{} - Payload: {:?}",
+            peer,
+            String::from_utf8_lossy(&request)
+        );
+
+        let checker = checker.clone();
+        let response =
+            match task::spawn_blocking(move || handle_frame(&request, encoding, &checker)).await {
+                Ok(Response::Malformed) => {
+                    error!("{} - Malformed request", peer);
+                    return handle_malformed_request(&mut ws, peer, encoding).await;
+                }
+                Ok(response) => encoding.encode(&response),
+                Err(e) => {
+                    error!("{} - Primality check failed: {:?}", peer, e);
+                    break;
+                }
+            };
+
+        match ws.write_all(&response).await {
+            Ok(_) => {
+                debug!("{} - Response: {:?}", peer, response);
+            }
+            Err(e) => {
+                error!(
+                    "{} - Cannot write to socket. Dropping connection: {:?}",
+                    peer, e
+                );
+                break;
+            }
+        }
+    }
+
+    let stats = checker.cache_stats();
+    info!(
+        "{} - Prime cache hits: {}, misses: {}",
+        peer, stats.hits, stats.misses
+    );
+
+    info!("{} - Ending connection", peer);
+}
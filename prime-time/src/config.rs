@@ -0,0 +1,101 @@
+use std::{env, str::FromStr};
+
+use crate::Encoding;
+
+/// Default upper bound for a single request line, including the trailing
+/// newline.
+pub const DEFAULT_MAX_REQUEST_SIZE_BYTES: usize = 64 * 1024;
+/// Numbers up to this value are answered from a sieve built at startup.
+pub const DEFAULT_SIEVE_LIMIT: u64 = 1_000_000;
+/// Largest sieve limit. The sieve takes one byte per number, so larger
+/// limits are lowered to this one, about 100 MB.
+pub const MAX_SIEVE_LIMIT: u64 = 100_000_000;
+/// Number of answers for numbers above the sieve limit kept in memory.
+pub const DEFAULT_PRIME_CACHE_CAPACITY: usize = 10_000;
+
+/// Concurrency model used to serve connections.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ServerMode {
+    /// One OS thread per connection, blocking IO.
+    #[default]
+    Threads,
+    /// Tokio tasks, with primality checks on the blocking thread pool.
+    Async,
+}
+
+impl FromStr for ServerMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "threads" => Ok(Self::Threads),
+            "async" => Ok(Self::Async),
+            _ => Err("unknown server mode"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Address the line protocol listener binds to.
+    pub bind_addr: String,
+    /// Address of the HTTP gateway. `None` disables it.
+    pub http_bind_addr: Option<String>,
+    pub mode: ServerMode,
+    /// Encoding spoken on the listener. `None` negotiates it from the first
+    /// byte of every connection.
+    pub encoding: Option<Encoding>,
+    /// Maximum number of bytes a client can send before a newline. Longer
+    /// requests are answered with a malformed response.
+    pub max_request_size: usize,
+    /// Largest number covered by the precomputed sieve, at most
+    /// [`MAX_SIEVE_LIMIT`].
+    pub sieve_limit: u64,
+    /// Capacity of the LRU cache for larger numbers. 0 disables the cache.
+    pub prime_cache_capacity: usize,
+}
+
+impl Config {
+    /// Reads the configuration from the environment, falling back to the
+    /// defaults for missing or unparsable values.
+    ///
+    /// - `BIND_ADDR` (defaults to [`utils::addr`])
+    /// - `HTTP_BIND_ADDR` (unset disables the HTTP gateway)
+    /// - `SERVER_MODE` (`threads` or `async`)
+    /// - `ENCODING` (`auto`, `json`, `msgpack` or `cbor`)
+    /// - `MAX_REQUEST_SIZE_BYTES`
+    /// - `SIEVE_LIMIT` (lowered to [`MAX_SIEVE_LIMIT`])
+    /// - `PRIME_CACHE_CAPACITY`
+    pub fn from_env() -> Self {
+        Self {
+            bind_addr: env_or("BIND_ADDR", utils::addr().to_string()),
+            http_bind_addr: env::var("HTTP_BIND_ADDR").ok(),
+            mode: env_or("SERVER_MODE", ServerMode::default()),
+            encoding: env::var("ENCODING").ok().and_then(|v| v.parse().ok()),
+            max_request_size: env_or("MAX_REQUEST_SIZE_BYTES", DEFAULT_MAX_REQUEST_SIZE_BYTES),
+            sieve_limit: env_or("SIEVE_LIMIT", DEFAULT_SIEVE_LIMIT).min(MAX_SIEVE_LIMIT),
+            prime_cache_capacity: env_or("PRIME_CACHE_CAPACITY", DEFAULT_PRIME_CACHE_CAPACITY),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addr: utils::addr().to_string(),
+            http_bind_addr: None,
+            mode: ServerMode::default(),
+            encoding: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE_BYTES,
+            sieve_limit: DEFAULT_SIEVE_LIMIT,
+            prime_cache_capacity: DEFAULT_PRIME_CACHE_CAPACITY,
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,161 @@
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::Response;
+
+/// First byte sent by a client that wants to talk MessagePack.
+pub const MESSAGE_PACK_PREAMBLE: u8 = b'M';
+/// First byte sent by a client that wants to talk CBOR.
+pub const CBOR_PREAMBLE: u8 = b'C';
+
+/// Wire encoding of requests and responses.
+///
+/// JSON requests are newline-delimited. MessagePack and CBOR requests are
+/// framed by a 4-byte big-endian length prefix followed by the payload, and
+/// responses use the same framing as the requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Picks the encoding announced by the first byte of a connection.
+    ///
+    /// Neither preamble can start a JSON document, so any other byte means the
+    /// client is speaking plain JSON and belongs to its first request. Returns
+    /// `true` when the byte is a preamble that must be consumed.
+    pub fn negotiate(first_byte: u8) -> (Self, bool) {
+        match first_byte {
+            MESSAGE_PACK_PREAMBLE => (Self::MessagePack, true),
+            CBOR_PREAMBLE => (Self::Cbor, true),
+            _ => (Self::Json, false),
+        }
+    }
+
+    /// Decodes a frame payload, without its length prefix or newline.
+    pub fn decode(&self, payload: &[u8]) -> Option<Value> {
+        match self {
+            Encoding::Json => serde_json::from_slice(payload).ok(),
+            Encoding::MessagePack => rmp_serde::from_slice(payload).ok(),
+            Encoding::Cbor => ciborium::from_reader(payload).ok(),
+        }
+    }
+
+    /// Encodes a response, including its framing.
+    pub fn encode(&self, response: &Response) -> Vec<u8> {
+        let payload = match self {
+            Encoding::Json => return response.to_line().into_bytes(),
+            Encoding::MessagePack => rmp_serde::to_vec_named(&response.to_value())
+                .expect("responses are always serializable"),
+            Encoding::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(&response.to_value(), &mut payload)
+                    .expect("responses are always serializable");
+                payload
+            }
+        };
+
+        let length = u32::try_from(payload.len()).expect("responses are small");
+        let mut frame = length.to_be_bytes().to_vec();
+        frame.extend(payload);
+        frame
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "msgpack" | "messagepack" => Ok(Self::MessagePack),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err("unknown encoding"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{handle_frame, Encoding, PrimeChecker, Response};
+
+    fn to_message_pack(value: &Value) -> Vec<u8> {
+        rmp_serde::to_vec_named(value).unwrap()
+    }
+
+    fn to_cbor(value: &Value) -> Vec<u8> {
+        let mut payload = Vec::new();
+        ciborium::into_writer(value, &mut payload).unwrap();
+        payload
+    }
+
+    #[test]
+    fn negotiates_from_the_first_byte() {
+        assert_eq!(Encoding::negotiate(b'M'), (Encoding::MessagePack, true));
+        assert_eq!(Encoding::negotiate(b'C'), (Encoding::Cbor, true));
+        assert_eq!(Encoding::negotiate(b'{'), (Encoding::Json, false));
+        assert_eq!(Encoding::negotiate(b' '), (Encoding::Json, false));
+    }
+
+    #[test]
+    fn every_encoding_answers_the_same() {
+        let checker = PrimeChecker::new(100, 0);
+        let requests = [
+            json!({"method": "isPrime", "number": 7}),
+            json!({"method": "isPrime", "number": 8}),
+            json!({"method": "isPrime", "number": 7.0}),
+            json!({"method": "isPrime", "number": -7}),
+            json!({"method": "isPrime", "number": 18_446_744_073_709_551_557_u64}),
+            json!({"method": "isPrime", "number": "7"}),
+            json!({"method": "isComposite", "number": 7}),
+            json!({"number": 7}),
+            json!([1, 2, 3]),
+        ];
+
+        for request in requests {
+            let json = serde_json::to_vec(&request).unwrap();
+            let expected = handle_frame(&json, Encoding::Json, &checker);
+
+            let msgpack = handle_frame(&to_message_pack(&request), Encoding::MessagePack, &checker);
+            let cbor = handle_frame(&to_cbor(&request), Encoding::Cbor, &checker);
+
+            assert_eq!(msgpack, expected, "{request}");
+            assert_eq!(cbor, expected, "{request}");
+        }
+    }
+
+    #[test]
+    fn garbage_is_malformed() {
+        let checker = PrimeChecker::new(100, 0);
+
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let response = handle_frame(&[0xc1, 0xff, 0x00], encoding, &checker);
+            assert_eq!(response, Response::Malformed, "{encoding:?}");
+        }
+    }
+
+    #[test]
+    fn binary_responses_are_length_prefixed() {
+        let response = Response::Prime(true);
+
+        for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+            let frame = encoding.encode(&response);
+            let length = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+
+            assert_eq!(length, frame.len() - 4);
+            assert_eq!(encoding.decode(&frame[4..]), Some(response.to_value()));
+        }
+    }
+
+    #[test]
+    fn json_responses_are_newline_terminated() {
+        let frame = Encoding::Json.encode(&Response::Malformed);
+
+        assert_eq!(frame, b"{\"result\":\"failure\"}\n");
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use axum::{
+    extract::{DefaultBodyLimit, Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, task};
+
+use crate::{handle_request, handle_value, PrimeChecker, Response};
+
+/// Serves the HTTP gateway:
+///
+/// - `GET /isPrime?number=N`
+/// - `POST /isPrime` with the same JSON body as the line protocol
+///
+/// Both answer with the line protocol's JSON, using `400 Bad Request` for
+/// malformed requests.
+pub async fn run(
+    addr: &str,
+    max_request_size: usize,
+    checker: Arc<PrimeChecker>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("HTTP gateway listening on: {}", addr);
+
+    axum::serve(listener, router(max_request_size, checker)).await
+}
+
+pub fn router(max_request_size: usize, checker: Arc<PrimeChecker>) -> Router {
+    Router::new()
+        .route("/isPrime", get(query).post(body))
+        .layer(DefaultBodyLimit::max(max_request_size))
+        .with_state(checker)
+}
+
+async fn query(
+    State(checker): State<Arc<PrimeChecker>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> (StatusCode, Json<Value>) {
+    debug!("HTTP query: {:?}", params);
+
+    // The parameter is parsed as a JSON number so that floats and negative
+    // numbers follow the same rules as the line protocol
+    let number = params
+        .get("number")
+        .and_then(|n| serde_json::from_str::<Value>(n).ok())
+        .filter(Value::is_number);
+
+    let Some(number) = number else {
+        return into_http(Response::Malformed);
+    };
+
+    let request = json!({"method": "isPrime", "number": number});
+    check(move || handle_value(&request, &checker)).await
+}
+
+async fn body(State(checker): State<Arc<PrimeChecker>>, body: String) -> (StatusCode, Json<Value>) {
+    debug!("HTTP body: {:?}", body);
+
+    check(move || handle_request(&body, &checker)).await
+}
+
+async fn check<F>(f: F) -> (StatusCode, Json<Value>)
+where
+    F: FnOnce() -> Response + Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(response) => into_http(response),
+        Err(e) => {
+            error!("Primality check failed: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(Response::Malformed.to_value()),
+            )
+        }
+    }
+}
+
+fn into_http(response: Response) -> (StatusCode, Json<Value>) {
+    let status = match response {
+        Response::Prime(_) => StatusCode::OK,
+        Response::Malformed => StatusCode::BAD_REQUEST,
+    };
+
+    (status, Json(response.to_value()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Method, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::{http::router, PrimeChecker};
+
+    async fn send(method: Method, uri: &str, body: &str) -> (StatusCode, Value) {
+        let checker = Arc::new(PrimeChecker::new(100, 0));
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router(64, checker).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn get_answers_with_the_line_protocol_rules() {
+        let prime = json!({"method": "isPrime", "prime": true});
+        let not_prime = json!({"method": "isPrime", "prime": false});
+
+        assert_eq!(
+            send(Method::GET, "/isPrime?number=7", "").await,
+            (StatusCode::OK, prime)
+        );
+        assert_eq!(
+            send(Method::GET, "/isPrime?number=-7", "").await,
+            (StatusCode::OK, not_prime.clone())
+        );
+        assert_eq!(
+            send(Method::GET, "/isPrime?number=7.0", "").await,
+            (StatusCode::OK, not_prime)
+        );
+    }
+
+    #[tokio::test]
+    async fn get_rejects_missing_and_non_numeric_numbers() {
+        let failure = json!({"result": "failure"});
+
+        for uri in [
+            "/isPrime",
+            "/isPrime?number=seven",
+            "/isPrime?number=%227%22",
+        ] {
+            assert_eq!(
+                send(Method::GET, uri, "").await,
+                (StatusCode::BAD_REQUEST, failure.clone()),
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn post_accepts_a_line_protocol_request() {
+        let (status, body) = send(
+            Method::POST,
+            "/isPrime",
+            r#"{"method":"isPrime","number":97}"#,
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"method": "isPrime", "prime": true}));
+    }
+
+    #[tokio::test]
+    async fn post_rejects_malformed_requests() {
+        let (status, body) = send(Method::POST, "/isPrime", r#"{"method":"isPrime"}"#).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, json!({"result": "failure"}));
+    }
+}
@@ -0,0 +1,24 @@
+mod config;
+pub use config::{Config, ServerMode, MAX_SIEVE_LIMIT};
+
+mod encoding;
+pub use encoding::{Encoding, CBOR_PREAMBLE, MESSAGE_PACK_PREAMBLE};
+
+mod reader;
+pub use reader::{
+    negotiate_encoding, negotiate_encoding_async, read_frame, read_frame_async, read_request_line,
+    read_request_line_async, ReadLineError,
+};
+
+mod primality;
+pub use primality::{CacheStats, PrimeChecker};
+
+mod request;
+pub use request::{handle_frame, handle_request, handle_value, parse_request, Request, Response};
+
+#[cfg(test)]
+mod test_utils;
+
+pub mod asynchronous;
+pub mod http;
+pub mod threaded;
@@ -0,0 +1,155 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use lru::LruCache;
+
+use crate::MAX_SIEVE_LIMIT;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Answers primality checks for non-negative integers.
+///
+/// Numbers up to `sieve_limit`, at most [`MAX_SIEVE_LIMIT`], are looked up in
+/// a sieve built once at startup. Larger numbers are checked with `is_prime` and the answer is kept
+/// in a bounded LRU cache, since the same number always has the same answer.
+pub struct PrimeChecker {
+    sieve: Vec<bool>,
+    cache: Option<Mutex<LruCache<u64, bool>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PrimeChecker {
+    /// A `cache_capacity` of 0 disables the cache.
+    pub fn new(sieve_limit: u64, cache_capacity: usize) -> Self {
+        Self {
+            sieve: sieve(sieve_limit),
+            cache: NonZeroUsize::new(cache_capacity).map(|c| Mutex::new(LruCache::new(c))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_prime(&self, number: u64) -> bool {
+        if let Some(is_prime) = usize::try_from(number).ok().and_then(|n| self.sieve.get(n)) {
+            return *is_prime;
+        }
+
+        let Some(cache) = &self.cache else {
+            return is_prime::is_prime(number.to_string().as_ref());
+        };
+
+        if let Some(is_prime) = cache.lock().unwrap().get(&number) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return *is_prime;
+        }
+
+        // The check runs without holding the lock so that other connections
+        // are not blocked on a slow number
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let is_prime = is_prime::is_prime(number.to_string().as_ref());
+        cache.lock().unwrap().put(number, is_prime);
+
+        is_prime
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sieve of Eratosthenes over `0..=limit`.
+fn sieve(limit: u64) -> Vec<bool> {
+    // Lowered to a size that fits in memory, and in a `usize` everywhere
+    let limit = usize::try_from(limit.min(MAX_SIEVE_LIMIT)).unwrap_or(usize::MAX);
+
+    let mut sieve = vec![true; limit + 1];
+    sieve[0] = false;
+    if limit >= 1 {
+        sieve[1] = false;
+    }
+
+    let mut i = 2;
+    while i * i <= limit {
+        if sieve[i] {
+            for multiple in (i * i..=limit).step_by(i) {
+                sieve[multiple] = false;
+            }
+        }
+        i += 1;
+    }
+
+    sieve
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_utils::reference_is_prime, CacheStats, PrimeChecker};
+
+    #[test]
+    fn sieve_matches_trial_division() {
+        let checker = PrimeChecker::new(10_000, 0);
+
+        for n in 0..=10_000 {
+            assert_eq!(checker.is_prime(n), reference_is_prime(n), "n = {n}");
+        }
+    }
+
+    #[test]
+    fn numbers_above_the_sieve_are_checked() {
+        let checker = PrimeChecker::new(10, 16);
+
+        for n in 0..=1_000 {
+            assert_eq!(checker.is_prime(n), reference_is_prime(n), "n = {n}");
+        }
+        assert!(checker.is_prime(18_446_744_073_709_551_557));
+        assert!(!checker.is_prime(u64::MAX));
+    }
+
+    #[test]
+    fn repeated_numbers_are_served_from_the_cache() {
+        let checker = PrimeChecker::new(10, 16);
+
+        checker.is_prime(7); // From the sieve, not counted
+        checker.is_prime(1_000_003);
+        checker.is_prime(1_000_003);
+        checker.is_prime(1_000_004);
+
+        assert_eq!(checker.cache_stats(), CacheStats { hits: 1, misses: 2 });
+    }
+
+    #[test]
+    fn cache_evicts_the_least_recently_used_number() {
+        let checker = PrimeChecker::new(0, 2);
+
+        checker.is_prime(101);
+        checker.is_prime(103);
+        checker.is_prime(101);
+        checker.is_prime(107); // Evicts 103
+        checker.is_prime(103);
+
+        assert_eq!(checker.cache_stats(), CacheStats { hits: 1, misses: 4 });
+    }
+
+    #[test]
+    fn disabled_cache_does_not_count() {
+        let checker = PrimeChecker::new(0, 0);
+
+        assert!(checker.is_prime(101));
+        assert!(checker.is_prime(101));
+
+        assert_eq!(checker.cache_stats(), CacheStats { hits: 0, misses: 0 });
+    }
+}
@@ -0,0 +1,322 @@
+use std::io::{self, BufRead, Read};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::Encoding;
+
+#[derive(Debug)]
+pub enum ReadLineError {
+    /// The client sent more than the allowed number of bytes without a
+    /// newline, or announced a frame larger than that.
+    TooLong,
+    /// The line is not valid UTF-8.
+    InvalidUtf8,
+    /// The underlying socket failed. The connection cannot be recovered.
+    Io(io::Error),
+}
+
+/// Reads a single newline-terminated request of at most `max_bytes` bytes
+/// (newline included).
+///
+/// Returns `Ok(None)` once the client has closed the connection. At most
+/// `max_bytes + 1` bytes are buffered, so a client that never sends a newline
+/// cannot exhaust memory.
+pub fn read_request_line<R: BufRead>(
+    reader: &mut R,
+    max_bytes: usize,
+) -> Result<Option<String>, ReadLineError> {
+    let mut buffer = Vec::new();
+
+    let bytes = reader
+        .by_ref()
+        .take(read_limit(max_bytes))
+        .read_until(b'\n', &mut buffer)
+        .map_err(ReadLineError::Io)?;
+
+    into_line(buffer, bytes, max_bytes)
+}
+
+/// Async counterpart of [`read_request_line`] with the same limits.
+pub async fn read_request_line_async<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_bytes: usize,
+) -> Result<Option<String>, ReadLineError> {
+    let mut buffer = Vec::new();
+
+    let bytes = reader
+        .take(read_limit(max_bytes))
+        .read_until(b'\n', &mut buffer)
+        .await
+        .map_err(ReadLineError::Io)?;
+
+    into_line(buffer, bytes, max_bytes)
+}
+
+/// Peeks at the first byte of a connection to pick its encoding, consuming
+/// it if it is a preamble.
+///
+/// Returns `Ok(None)` if the client disconnected before sending anything.
+pub fn negotiate_encoding<R: BufRead>(reader: &mut R) -> io::Result<Option<Encoding>> {
+    let Some(&first_byte) = reader.fill_buf()?.first() else {
+        return Ok(None);
+    };
+
+    let (encoding, is_preamble) = Encoding::negotiate(first_byte);
+    if is_preamble {
+        reader.consume(1);
+    }
+
+    Ok(Some(encoding))
+}
+
+/// Async counterpart of [`negotiate_encoding`].
+pub async fn negotiate_encoding_async<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Encoding>> {
+    let Some(&first_byte) = reader.fill_buf().await?.first() else {
+        return Ok(None);
+    };
+
+    let (encoding, is_preamble) = Encoding::negotiate(first_byte);
+    if is_preamble {
+        reader.consume(1);
+    }
+
+    Ok(Some(encoding))
+}
+
+/// Reads a single request frame of at most `max_bytes` bytes.
+///
+/// JSON frames are lines, as returned by [`read_request_line`]. Other
+/// encodings are length-prefixed, and a prefix above `max_bytes` is rejected
+/// before the payload is read.
+pub fn read_frame<R: BufRead>(
+    reader: &mut R,
+    encoding: Encoding,
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>, ReadLineError> {
+    if encoding == Encoding::Json {
+        return Ok(read_request_line(reader, max_bytes)?.map(String::into_bytes));
+    }
+
+    // A disconnect between two frames is not an error
+    if reader.fill_buf().map_err(ReadLineError::Io)?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut length = [0; 4];
+    reader.read_exact(&mut length).map_err(ReadLineError::Io)?;
+
+    let length = frame_length(length, max_bytes)?;
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).map_err(ReadLineError::Io)?;
+
+    Ok(Some(payload))
+}
+
+/// Async counterpart of [`read_frame`].
+pub async fn read_frame_async<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    encoding: Encoding,
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>, ReadLineError> {
+    if encoding == Encoding::Json {
+        return Ok(read_request_line_async(reader, max_bytes)
+            .await?
+            .map(String::into_bytes));
+    }
+
+    // A disconnect between two frames is not an error
+    if reader
+        .fill_buf()
+        .await
+        .map_err(ReadLineError::Io)?
+        .is_empty()
+    {
+        return Ok(None);
+    }
+
+    let mut length = [0; 4];
+    reader
+        .read_exact(&mut length)
+        .await
+        .map_err(ReadLineError::Io)?;
+
+    let length = frame_length(length, max_bytes)?;
+    let mut payload = vec![0; length];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(ReadLineError::Io)?;
+
+    Ok(Some(payload))
+}
+
+fn frame_length(prefix: [u8; 4], max_bytes: usize) -> Result<usize, ReadLineError> {
+    match usize::try_from(u32::from_be_bytes(prefix)) {
+        Ok(length) if length <= max_bytes => Ok(length),
+        _ => Err(ReadLineError::TooLong),
+    }
+}
+
+fn read_limit(max_bytes: usize) -> u64 {
+    u64::try_from(max_bytes)
+        .unwrap_or(u64::MAX)
+        .saturating_add(1)
+}
+
+fn into_line(
+    buffer: Vec<u8>,
+    bytes: usize,
+    max_bytes: usize,
+) -> Result<Option<String>, ReadLineError> {
+    if bytes == 0 {
+        return Ok(None);
+    }
+
+    if buffer.len() > max_bytes {
+        return Err(ReadLineError::TooLong);
+    }
+
+    match String::from_utf8(buffer) {
+        Ok(line) => Ok(Some(line)),
+        Err(_) => Err(ReadLineError::InvalidUtf8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufRead, BufReader, Cursor, Read};
+
+    use crate::{
+        negotiate_encoding, read_frame, read_request_line, read_request_line_async, Encoding,
+        ReadLineError,
+    };
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+        }
+    }
+
+    #[test]
+    fn reads_consecutive_lines() {
+        let mut reader = Cursor::new(b"{\"a\":1}\n{\"b\":2}\n".to_vec());
+
+        let first = read_request_line(&mut reader, 64).unwrap();
+        let second = read_request_line(&mut reader, 64).unwrap();
+        let end = read_request_line(&mut reader, 64).unwrap();
+
+        assert_eq!(first, Some("{\"a\":1}\n".to_string()));
+        assert_eq!(second, Some("{\"b\":2}\n".to_string()));
+        assert_eq!(end, None);
+    }
+
+    #[test]
+    fn accepts_a_line_of_exactly_the_maximum_size() {
+        let mut reader = Cursor::new(b"1234\n".to_vec());
+
+        let line = read_request_line(&mut reader, 5).unwrap();
+
+        assert_eq!(line, Some("1234\n".to_string()));
+    }
+
+    #[test]
+    fn rejects_a_line_above_the_maximum_size() {
+        let mut reader = Cursor::new(b"12345\n".to_vec());
+
+        let res = read_request_line(&mut reader, 5);
+
+        assert!(matches!(res, Err(ReadLineError::TooLong)));
+    }
+
+    #[test]
+    fn does_not_buffer_more_than_the_maximum_size() {
+        let mut reader = BufReader::new(io::repeat(b'a').take(1024 * 1024));
+
+        let res = read_request_line(&mut reader, 16);
+
+        assert!(matches!(res, Err(ReadLineError::TooLong)));
+        // Only `max_bytes + 1` bytes were consumed from the source
+        let rest = reader.fill_buf().unwrap().len() + reader.into_inner().limit() as usize;
+        assert_eq!(rest, 1024 * 1024 - 17);
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        let mut reader = Cursor::new(vec![0xff, 0xfe, b'\n']);
+
+        let res = read_request_line(&mut reader, 64);
+
+        assert!(matches!(res, Err(ReadLineError::InvalidUtf8)));
+    }
+
+    #[test]
+    fn surfaces_read_errors() {
+        let mut reader = BufReader::new(FailingReader);
+
+        let res = read_request_line(&mut reader, 64);
+
+        assert!(matches!(res, Err(ReadLineError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn async_reader_applies_the_same_limits() {
+        let mut reader = tokio::io::BufReader::new(&b"1234\n12345\n"[..]);
+
+        let first = read_request_line_async(&mut reader, 5).await.unwrap();
+        let second = read_request_line_async(&mut reader, 5).await;
+
+        assert_eq!(first, Some("1234\n".to_string()));
+        assert!(matches!(second, Err(ReadLineError::TooLong)));
+    }
+
+    #[test]
+    fn negotiation_consumes_only_preambles() {
+        let mut msgpack = Cursor::new(b"M\x00\x00\x00\x01\xc0".to_vec());
+        let mut json = Cursor::new(b"{}\n".to_vec());
+        let mut empty = Cursor::new(Vec::new());
+
+        assert_eq!(
+            negotiate_encoding(&mut msgpack).unwrap(),
+            Some(Encoding::MessagePack)
+        );
+        assert_eq!(msgpack.position(), 1);
+        assert_eq!(negotiate_encoding(&mut json).unwrap(), Some(Encoding::Json));
+        assert_eq!(json.position(), 0);
+        assert_eq!(negotiate_encoding(&mut empty).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_length_prefixed_frames() {
+        let mut reader = Cursor::new(b"\x00\x00\x00\x02ab\x00\x00\x00\x00".to_vec());
+
+        let first = read_frame(&mut reader, Encoding::Cbor, 16).unwrap();
+        let second = read_frame(&mut reader, Encoding::Cbor, 16).unwrap();
+        let end = read_frame(&mut reader, Encoding::Cbor, 16).unwrap();
+
+        assert_eq!(first, Some(b"ab".to_vec()));
+        assert_eq!(second, Some(vec![]));
+        assert_eq!(end, None);
+    }
+
+    #[test]
+    fn rejects_frames_above_the_maximum_size_before_reading_them() {
+        let mut reader = Cursor::new(b"\xff\xff\xff\xff".to_vec());
+
+        let res = read_frame(&mut reader, Encoding::MessagePack, 16);
+
+        assert!(matches!(res, Err(ReadLineError::TooLong)));
+    }
+
+    #[test]
+    fn truncated_frames_are_io_errors() {
+        let mut reader = Cursor::new(b"\x00\x00\x00\x08abc".to_vec());
+
+        let res = read_frame(&mut reader, Encoding::MessagePack, 16);
+
+        assert!(matches!(res, Err(ReadLineError::Io(_))));
+    }
+}
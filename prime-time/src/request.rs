@@ -0,0 +1,188 @@
+use serde_json::{json, Value};
+
+use crate::{Encoding, PrimeChecker};
+
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    /// Answer to a well-formed `isPrime` request.
+    Prime(bool),
+    /// The request was malformed. The connection must be closed after sending
+    /// this response.
+    Malformed,
+}
+
+impl Response {
+    /// Encoding-independent representation of the response.
+    pub fn to_value(&self) -> Value {
+        match self {
+            Response::Prime(is_prime) => json!({"method": "isPrime", "prime": is_prime}),
+            Response::Malformed => json!({"result": "failure"}),
+        }
+    }
+
+    /// Newline-terminated JSON representation sent over the wire.
+    pub fn to_line(&self) -> String {
+        format!("{}\n", self.to_value())
+    }
+}
+
+/// Validates a single JSON request and answers it.
+pub fn handle_request(json_request: &str, checker: &PrimeChecker) -> Response {
+    match serde_json::from_str::<Value>(json_request) {
+        Ok(request) => handle_value(&request, checker),
+        Err(_) => Response::Malformed,
+    }
+}
+
+/// Decodes a single frame in the given encoding and answers it.
+pub fn handle_frame(frame: &[u8], encoding: Encoding, checker: &PrimeChecker) -> Response {
+    match encoding.decode(frame) {
+        Some(request) => handle_value(&request, checker),
+        None => Response::Malformed,
+    }
+}
+
+/// A validated `isPrime` request.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// A non-negative integer whose primality must be checked.
+    Natural(u64),
+    /// A float or a negative integer. These are never prime.
+    NotNatural,
+}
+
+/// Validates a decoded request without answering it.
+///
+/// Returns `None` if the request is malformed: it is not an object, the
+/// `method` is not `"isPrime"` or the `number` is not a JSON number.
+pub fn parse_request(request: &Value) -> Option<Request> {
+    let method = request.get("method")?;
+    let number = request.get("number")?;
+
+    if *method != json!("isPrime") || !number.is_number() {
+        return None;
+    }
+
+    // Only non-negative integers convert to an u64. Floats, even integral
+    // ones such as `7.0`, do not.
+    match number.as_u64() {
+        Some(number) => Some(Request::Natural(number)),
+        None => Some(Request::NotNatural),
+    }
+}
+
+/// Validates an already decoded request and answers it. Every encoding goes
+/// through here so that they all share the same semantics.
+pub fn handle_value(request: &Value, checker: &PrimeChecker) -> Response {
+    match parse_request(request) {
+        Some(Request::Natural(number)) => Response::Prime(checker.is_prime(number)),
+        Some(Request::NotNatural) => Response::Prime(false),
+        None => Response::Malformed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use crate::{
+        handle_request, parse_request, test_utils::reference_is_prime, PrimeChecker, Request,
+        Response,
+    };
+
+    fn checker() -> PrimeChecker {
+        PrimeChecker::new(1_000, 64)
+    }
+
+    #[test]
+    fn parses_valid_requests() {
+        let cases = [
+            (
+                json!({"method": "isPrime", "number": 7}),
+                Request::Natural(7),
+            ),
+            (
+                json!({"method": "isPrime", "number": u64::MAX}),
+                Request::Natural(u64::MAX),
+            ),
+            (
+                json!({"method": "isPrime", "number": -7}),
+                Request::NotNatural,
+            ),
+            (
+                json!({"method": "isPrime", "number": 7.0}),
+                Request::NotNatural,
+            ),
+            (
+                json!({"method": "isPrime", "number": 7, "extra": null}),
+                Request::Natural(7),
+            ),
+        ];
+
+        for (request, expected) in cases {
+            assert_eq!(parse_request(&request), Some(expected), "{request}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases = [
+            json!({"method": "isPrime"}),
+            json!({"number": 7}),
+            json!({"method": "isprime", "number": 7}),
+            json!({"method": "isPrime", "number": "7"}),
+            json!({"method": "isPrime", "number": null}),
+            json!([{"method": "isPrime", "number": 7}]),
+            json!(7),
+        ];
+
+        for request in cases {
+            assert_eq!(parse_request(&request), None, "{request}");
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn naturals_match_the_reference(number in 0..1_000_000_000_000_u64) {
+            let line = json!({"method": "isPrime", "number": number}).to_string();
+
+            let response = handle_request(&line, &checker());
+
+            prop_assert_eq!(response, Response::Prime(reference_is_prime(number)));
+        }
+
+        #[test]
+        fn negative_numbers_are_not_prime(number in i64::MIN..0) {
+            let line = json!({"method": "isPrime", "number": number}).to_string();
+
+            prop_assert_eq!(handle_request(&line, &checker()), Response::Prime(false));
+        }
+
+        #[test]
+        fn floats_are_not_prime(number in any::<f64>().prop_filter("finite", |n| n.is_finite())) {
+            let line = json!({"method": "isPrime", "number": number}).to_string();
+
+            prop_assert_eq!(handle_request(&line, &checker()), Response::Prime(false));
+        }
+
+        #[test]
+        fn arbitrary_lines_never_panic(line in any::<String>()) {
+            let response = handle_request(&line, &checker());
+
+            // Whatever the answer, it must be consistent with the parser
+            let parsed = serde_json::from_str::<Value>(&line).ok();
+            let parsed = parsed.as_ref().and_then(parse_request);
+            prop_assert_eq!(response == Response::Malformed, parsed.is_none());
+        }
+
+        #[test]
+        fn responses_round_trip(is_prime in any::<bool>()) {
+            let response = Response::Prime(is_prime);
+
+            let decoded = serde_json::from_str::<Value>(&response.to_line()).unwrap();
+
+            prop_assert_eq!(decoded, json!({"method": "isPrime", "prime": is_prime}));
+        }
+    }
+}
@@ -0,0 +1,7 @@
+/// Primality by trial division, slow but obviously right.
+pub fn reference_is_prime(n: u64) -> bool {
+    n >= 2
+        && (2..)
+            .take_while(|d| d * d <= n)
+            .all(|d| !n.is_multiple_of(d))
+}
@@ -0,0 +1,278 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread::{self, ThreadId},
+};
+
+use log::{debug, error, info, warn};
+
+use crate::{
+    handle_frame, negotiate_encoding, read_frame, Config, Encoding, PrimeChecker, ReadLineError,
+    Response,
+};
+
+/// Serves every connection on its own OS thread with blocking IO.
+pub fn run(config: Config, checker: Arc<PrimeChecker>) -> io::Result<()> {
+    let listener = TcpListener::bind(&config.bind_addr)?;
+    info!("Server listening on: {} (threads)", config.bind_addr);
+
+    for stream in listener.incoming().flatten() {
+        let config = config.clone();
+        let checker = checker.clone();
+        thread::spawn(move || handle_connection(stream, thread::current().id(), config, checker));
+    }
+
+    Ok(())
+}
+
+fn handle_malformed_request<W: Write>(writer: &mut W, tid: ThreadId, encoding: Encoding) {
+    let malformed_response = encoding.encode(&Response::Malformed);
+
+    match writer.write_all(&malformed_response) {
+        Ok(_) => {
+            debug!("{:?} - Malformed response: {:?}", tid, malformed_response);
+        }
+        Err(e) => {
+            error!("{:?} - Cannot write to socket: {:?}", tid, e);
+        }
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    tid: ThreadId,
+    config: Config,
+    checker: Arc<PrimeChecker>,
+) {
+    info!(
+        "{:?} - Established connection with: {:?}",
+        tid,
+        stream.peer_addr()
+    );
+
+    match stream.try_clone() {
+        Ok(read_stream) => serve(
+            BufReader::new(read_stream),
+            &mut stream,
+            tid,
+            &config,
+            &checker,
+        ),
+        Err(e) => {
+            error!(
+                "{:?} - Cannot clone socket. Dropping connection: {:?}",
+                tid, e
+            );
+        }
+    }
+
+    let stats = checker.cache_stats();
+    info!(
+        "{:?} - Prime cache hits: {}, misses: {}",
+        tid, stats.hits, stats.misses
+    );
+
+    info!(
+        "{:?} - Ending connection with: {:?}",
+        tid,
+        stream.peer_addr()
+    );
+}
+
+/// Answers requests read from `reader` until the client disconnects or sends a
+/// malformed request. Each malformed request gets exactly one failure
+/// response, after which nothing else is read or written.
+pub fn serve<R: BufRead, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    tid: ThreadId,
+    config: &Config,
+    checker: &PrimeChecker,
+) {
+    let encoding = match config.encoding {
+        Some(encoding) => encoding,
+        None => match negotiate_encoding(&mut reader) {
+            Ok(Some(encoding)) => encoding,
+            Ok(None) => {
+                warn!("{:?} - Client disconnected", tid);
+                return;
+            }
+            Err(e) => {
+                error!(
+                    "{:?} - Cannot read from socket. Dropping connection: {:?}",
+                    tid, e
+                );
+                return;
+            }
+        },
+    };
+    debug!("{:?} - Encoding: {:?}", tid, encoding);
+
+    loop {
+        let request = match read_frame(&mut reader, encoding, config.max_request_size) {
+            Ok(Some(frame)) => {
+                info!(
+                    "Read a {:?} payload of size {} bytes",
+                    encoding,
+                    frame.len()
+                );
+                frame
+            }
+            Ok(None) => {
+                warn!("{:?} - Client disconnected", tid);
+                return;
+            }
+            Err(ReadLineError::TooLong) => {
+                error!(
+                    "{:?} - Request exceeds {} bytes",
+                    tid, config.max_request_size
+                );
+                return handle_malformed_request(writer, tid, encoding);
+            }
+            Err(ReadLineError::InvalidUtf8) => {
+                error!("{:?} - Request is not valid UTF-8", tid);
+                return handle_malformed_request(writer, tid, encoding);
+            }
+            Err(ReadLineError::Io(e)) => {
+                error!(
+                    "{:?} - Cannot read from socket. Dropping connection: {:?}",
+                    tid, e
+                );
+                return;
+            }
+        };
+
+        debug!(
+            "{:?} - Payload: {:?}",
+            tid,
+            String::from_utf8_lossy(&request)
+        );
+
+        let response = match handle_frame(&request, encoding, checker) {
+            Response::Malformed => {
+                error!("{:?} - Malformed request: {:?}", tid, request);
+                return handle_malformed_request(writer, tid, encoding);
+            }
+            response => encoding.encode(&response),
+        };
+
+        match writer.write_all(&response) {
+            Ok(_) => {
+                debug!("{:?} - Response: {:?}", tid, response);
+            }
+            Err(e) => {
+                error!(
+                    "{:?} - Cannot write to socket. Dropping connection: {:?}",
+                    tid, e
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, thread};
+
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use crate::{handle_request, threaded::serve, Config, Encoding, PrimeChecker, Response};
+
+    fn run_session(input: &[u8], encoding: Option<Encoding>) -> Vec<u8> {
+        let checker = PrimeChecker::new(1_000, 64);
+        let config = Config {
+            encoding,
+            max_request_size: 128,
+            ..Config::default()
+        };
+        let mut output = Vec::new();
+
+        serve(
+            Cursor::new(input),
+            &mut output,
+            thread::current().id(),
+            &config,
+            &checker,
+        );
+
+        output
+    }
+
+    fn run_json_session(input: &[u8]) -> String {
+        String::from_utf8(run_session(input, Some(Encoding::Json))).unwrap()
+    }
+
+    fn request(number: u64) -> String {
+        format!("{}\n", json!({"method": "isPrime", "number": number}))
+    }
+
+    #[test]
+    fn answers_every_request_until_disconnect() {
+        let input = format!("{}{}{}", request(2), request(4), request(5));
+
+        let output = run_json_session(input.as_bytes());
+
+        assert_eq!(
+            output,
+            [true, false, true]
+                .map(|p| Response::Prime(p).to_line())
+                .concat()
+        );
+    }
+
+    #[test]
+    fn oversized_requests_get_one_failure_response() {
+        let input = format!("{}{}\n{}", request(2), "a".repeat(1_000), request(3));
+
+        let output = run_json_session(input.as_bytes());
+
+        assert_eq!(
+            output,
+            format!(
+                "{}{}",
+                Response::Prime(true).to_line(),
+                Response::Malformed.to_line()
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_gets_one_failure_response() {
+        let output = run_json_session(b"\xff\xfe\n{\"method\":\"isPrime\",\"number\":2}\n");
+
+        assert_eq!(output, Response::Malformed.to_line());
+    }
+
+    proptest! {
+        #[test]
+        fn malformed_lines_get_exactly_one_failure_response(
+            before in prop::collection::vec(0..10_000_u64, 0..5),
+            malformed in "[^\n]{0,100}".prop_filter("must be malformed", |line| {
+                handle_request(line, &PrimeChecker::new(0, 0)) == Response::Malformed
+            }),
+            after in prop::collection::vec(0..10_000_u64, 0..5),
+        ) {
+            let mut input: String = before.iter().map(|n| request(*n)).collect();
+            input.push_str(&malformed);
+            input.push('\n');
+            input.extend(after.iter().map(|n| request(*n)));
+
+            let output = run_json_session(input.as_bytes());
+
+            let lines = output.lines().collect::<Vec<_>>();
+            prop_assert_eq!(lines.len(), before.len() + 1);
+            prop_assert_eq!(
+                format!("{}\n", lines[before.len()]),
+                Response::Malformed.to_line()
+            );
+        }
+
+        #[test]
+        fn arbitrary_input_never_panics(input in prop::collection::vec(any::<u8>(), 0..512)) {
+            run_session(&input, None);
+        }
+    }
+}